@@ -0,0 +1,1465 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::macros::{self, Macro};
+use crate::{Error, Instruction, Reg, SymbolTable};
+
+/// A line of source
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// The file the line is in, empty for source given to `assemble`
+    pub file: String,
+    /// The line number, counting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "line {}", self.line)
+        } else {
+            write!(f, "{}:{}", self.file, self.line)
+        }
+    }
+}
+
+/// What a line of source is being assembled as part of
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpansionKind {
+    /// The named macro
+    Macro(String),
+    Rept,
+    /// The named file
+    Include(String),
+}
+
+/// A macro, REPT or INCLUDE, and where it was used
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub kind: ExpansionKind,
+    pub location: SourceLocation,
+}
+
+impl fmt::Display for Expansion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExpansionKind::Macro(name) => write!(f, "in macro {} at {}", name, self.location),
+            ExpansionKind::Rept => write!(f, "in REPT at {}", self.location),
+            ExpansionKind::Include(file) => write!(f, "in {} included at {}", file, self.location),
+        }
+    }
+}
+
+/// Why source couldn't be assembled
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    pub location: SourceLocation,
+    pub message: String,
+    /// The macros, REPTs and INCLUDEs the line came from, innermost first
+    pub expansions: Vec<Expansion>,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        for expansion in &self.expansions {
+            write!(f, "\n  {}", expansion)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+/// One line of source and what it assembled to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingLine {
+    /// The line number in its file, counting from 1
+    pub line: usize,
+    /// How many macros, REPTs and INCLUDEs the line is inside
+    pub depth: usize,
+    /// The address at the start of the line, or the value of an EQU or SET
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub source: String,
+}
+
+impl fmt::Display for ListingLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Lines from an expansion are marked with a +
+        let marker = if self.depth > 0 { '+' } else { ' ' };
+        write!(
+            f,
+            "{:5}{}{:04X} {:<11} {}",
+            self.line, marker, self.address, bytes, self.source
+        )
+    }
+}
+
+/// The output of [`assemble`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    /// The address of the first byte of `bytes`
+    pub origin: u16,
+    /// Everything assembled, from `origin` to the last byte. Gaps left by
+    /// ORG and DS are filled with zeros
+    pub bytes: Vec<u8>,
+    /// The start address given to END, if any
+    pub entry: Option<u16>,
+    pub listing: Vec<ListingLine>,
+    /// The labels, for disassembly and the debugger
+    pub symbols: SymbolTable,
+}
+
+/// Assemble Intel syntax 8080 source
+///
+/// Lines are `[label:] [mnemonic [operands]] [; comment]`. A label without
+/// a colon is allowed at the start of a line. The directives are ORG, DB,
+/// DW, DS, EQU, SET and END. Expressions can use `$` for the address of the
+/// current line, decimal numbers, hex, octal and binary numbers with an
+/// `H`, `O`/`Q` or `B` suffix (or a `0x` or `$` prefix for hex), character
+/// literals, and the operators `+ - * / MOD SHL SHR NOT AND OR XOR HIGH LOW`
+/// and `EQ NE LT LE GT GE`.
+///
+/// There's also macro assembly in the style of Intel and Digital Research
+/// assemblers:
+///
+/// - `NAME MACRO params` ... `ENDM` defines a macro, used as `NAME args`.
+///   Arguments containing commas can be put in `<>`. `&` joins a parameter
+///   to the text next to it, and `LOCAL names` at the start of the body
+///   gives each expansion its own labels
+/// - `REPT count` ... `ENDM` repeats lines
+/// - `IF expr` ... `ELSE` ... `ENDIF` assembles lines when `expr` isn't zero
+/// - `INCLUDE file` assembles another file, relative to the including one
+pub fn assemble(source: &str) -> Result<Assembly, AssemblyError> {
+    Assembler::default().assemble(&macros::source_lines("", source))
+}
+
+/// Assemble the file at `path`, see [`assemble`]
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Assembly, AssemblyError> {
+    let file = path.as_ref().display().to_string();
+    let source = fs::read_to_string(&path).map_err(|err| AssemblyError {
+        location: SourceLocation {
+            file: file.clone(),
+            line: 0,
+        },
+        message: format!("can't read {}: {}", file, err),
+        expansions: Vec::new(),
+    })?;
+    Assembler::default().assemble(&macros::source_lines(&file, &source))
+}
+
+/// A line of source and where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SourceLine {
+    pub(crate) text: String,
+    pub(crate) location: SourceLocation,
+}
+
+/// A line split into its parts
+struct Statement<'a> {
+    label: Option<&'a str>,
+    mnemonic: &'a str,
+    operands: &'a str,
+}
+
+/// An IF being assembled
+struct Conditional {
+    location: SourceLocation,
+    /// Whether the lines under the IF or ELSE being read are assembled
+    active: bool,
+    /// Whether the IF's condition held
+    held: bool,
+    /// Whether the lines around the IF are assembled
+    enclosing: bool,
+    seen_else: bool,
+}
+
+/// How deep macros, REPTs and INCLUDEs can nest, to catch recursion
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymbolKind {
+    Label,
+    Equate,
+    Set,
+}
+
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    value: u16,
+    kind: SymbolKind,
+}
+
+#[derive(Debug, Default)]
+struct Assembler {
+    // Keyed by upper case name, as symbols aren't case sensitive
+    symbols: BTreeMap<String, Symbol>,
+    // Names defined so far in the current pass
+    defined: HashSet<String>,
+    // EQUs the first pass couldn't evaluate, with the address they were at,
+    // worked out when the final pass needs them
+    pending: HashMap<String, (String, u16)>,
+    // The pending EQUs being worked out, to catch ones that refer to
+    // themselves
+    resolving: RefCell<HashSet<String>>,
+    final_pass: bool,
+    address: u16,
+    // Set when `address` has gone past 0xffff, until the next ORG
+    past_end: bool,
+    output: BTreeMap<u16, u8>,
+    entry: Option<u16>,
+    // Set by END
+    ended: bool,
+    listing: Vec<ListingLine>,
+    // Keyed by upper case name
+    macros: HashMap<String, Macro>,
+    // The expansions being assembled, outermost first
+    expansions: Vec<Expansion>,
+    // Numbers the labels made for LOCAL
+    locals: usize,
+}
+
+/// The number of operands each instruction takes
+const MNEMONICS: &[(&str, usize)] = &[
+    ("NOP", 0),
+    ("LXI", 2),
+    ("STAX", 1),
+    ("INX", 1),
+    ("INR", 1),
+    ("DCR", 1),
+    ("MVI", 2),
+    ("RLC", 0),
+    ("DAD", 1),
+    ("LDAX", 1),
+    ("DCX", 1),
+    ("RRC", 0),
+    ("RAL", 0),
+    ("RAR", 0),
+    ("SHLD", 1),
+    ("CMA", 0),
+    ("DAA", 0),
+    ("LHLD", 1),
+    ("STA", 1),
+    ("STC", 0),
+    ("LDA", 1),
+    ("CMC", 0),
+    ("MOV", 2),
+    ("HLT", 0),
+    ("ADD", 1),
+    ("ADC", 1),
+    ("SUB", 1),
+    ("SBB", 1),
+    ("ANA", 1),
+    ("XRA", 1),
+    ("ORA", 1),
+    ("CMP", 1),
+    ("RNZ", 0),
+    ("POP", 1),
+    ("JNZ", 1),
+    ("JMP", 1),
+    ("CNZ", 1),
+    ("PUSH", 1),
+    ("ADI", 1),
+    ("RST", 1),
+    ("RZ", 0),
+    ("RET", 0),
+    ("JZ", 1),
+    ("CZ", 1),
+    ("CALL", 1),
+    ("ACI", 1),
+    ("RNC", 0),
+    ("JNC", 1),
+    ("OUT", 1),
+    ("CNC", 1),
+    ("SUI", 1),
+    ("RC", 0),
+    ("JC", 1),
+    ("IN", 1),
+    ("CC", 1),
+    ("SBI", 1),
+    ("RPO", 0),
+    ("JPO", 1),
+    ("XTHL", 0),
+    ("CPO", 1),
+    ("ANI", 1),
+    ("RPE", 0),
+    ("PCHL", 0),
+    ("JPE", 1),
+    ("XCHG", 0),
+    ("CPE", 1),
+    ("XRI", 1),
+    ("RP", 0),
+    ("JP", 1),
+    ("DI", 0),
+    ("CP", 1),
+    ("ORI", 1),
+    ("RM", 0),
+    ("SPHL", 0),
+    ("JM", 1),
+    ("EI", 0),
+    ("CM", 1),
+    ("CPI", 1),
+];
+
+const DIRECTIVES: &[&str] = &[
+    "ORG", "DB", "DW", "DS", "EQU", "SET", "END", "MACRO", "ENDM", "LOCAL", "REPT", "IF", "ELSE",
+    "ENDIF", "INCLUDE",
+];
+
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    DIRECTIVES.contains(&word.as_str()) || MNEMONICS.iter().any(|&(name, _)| name == word)
+}
+
+impl Assembler {
+    fn assemble(mut self, lines: &[SourceLine]) -> Result<Assembly, AssemblyError> {
+        self.pass(lines, false)?;
+        self.pass(lines, true)?;
+        Ok(self.finish())
+    }
+
+    fn pass(&mut self, lines: &[SourceLine], final_pass: bool) -> Result<(), AssemblyError> {
+        self.final_pass = final_pass;
+        self.defined.clear();
+        if !final_pass {
+            self.pending.clear();
+        }
+        self.address = 0;
+        self.past_end = false;
+        self.output.clear();
+        self.entry = None;
+        self.ended = false;
+        self.listing.clear();
+        self.macros.clear();
+        self.locals = 0;
+        self.block(lines)
+    }
+
+    /// Assemble the lines of a file or expansion
+    fn block(&mut self, lines: &[SourceLine]) -> Result<(), AssemblyError> {
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut index = 0;
+        while index < lines.len() && !self.ended {
+            let line = &lines[index];
+            index += 1;
+            let error =
+                |assembler: &Self, message: String| assembler.error(&line.location, message);
+
+            let statement = self.parse(&line.text);
+            let upper = statement.mnemonic.to_ascii_uppercase();
+            let active = conditionals
+                .last()
+                .is_none_or(|conditional| conditional.active);
+            match upper.as_str() {
+                "IF" => {
+                    let held = active
+                        && self
+                            .evaluate_now(statement.operands)
+                            .map_err(|message| error(self, message))?
+                            != 0;
+                    conditionals.push(Conditional {
+                        location: line.location.clone(),
+                        active: held,
+                        held,
+                        enclosing: active,
+                        seen_else: false,
+                    });
+                }
+                "ELSE" => match conditionals.last_mut() {
+                    Some(conditional) if !conditional.seen_else => {
+                        conditional.active = conditional.enclosing && !conditional.held;
+                        conditional.seen_else = true;
+                    }
+                    Some(_) => return Err(error(self, "ELSE after ELSE".to_owned())),
+                    None => return Err(error(self, "ELSE without IF".to_owned())),
+                },
+                "ENDIF" => {
+                    if conditionals.pop().is_none() {
+                        return Err(error(self, "ENDIF without IF".to_owned()));
+                    }
+                }
+                _ if !active => {
+                    // Skip whole blocks, so an ENDM in one isn't taken as the
+                    // end of anything
+                    if upper == "MACRO" || upper == "REPT" {
+                        index = macros::collect_block(lines, index)
+                            .map_or(lines.len(), |(_, after)| after);
+                    }
+                    continue;
+                }
+                "MACRO" => {
+                    let Some(name) = statement.label else {
+                        return Err(error(self, "MACRO needs a name".to_owned()));
+                    };
+                    if !is_name(name) || is_keyword(name) {
+                        return Err(error(self, format!("{} can't be used as a name", name)));
+                    }
+                    let (body, after) = macros::collect_block(lines, index)
+                        .ok_or_else(|| error(self, "MACRO without ENDM".to_owned()))?;
+                    index = after;
+                    let parameters = macros::split_arguments(statement.operands);
+                    self.macros
+                        .insert(name.to_ascii_uppercase(), Macro { parameters, body });
+                }
+                "REPT" => {
+                    let (body, after) = macros::collect_block(lines, index)
+                        .ok_or_else(|| error(self, "REPT without ENDM".to_owned()))?;
+                    index = after;
+                    if let Some(label) = statement.label {
+                        self.define(label, self.address, SymbolKind::Label)
+                            .map_err(|message| error(self, message))?;
+                    }
+                    let count = self
+                        .evaluate_now(statement.operands)
+                        .map_err(|message| error(self, message))?;
+                    if !(0..=0xffff).contains(&count) {
+                        return Err(error(self, format!("{} isn't a valid REPT count", count)));
+                    }
+                    self.list(line, Line::Bytes(Vec::new()));
+                    for _ in 0..count {
+                        self.expand(ExpansionKind::Rept, &line.location, &body)?;
+                    }
+                    continue;
+                }
+                "ENDM" => return Err(error(self, "ENDM without MACRO or REPT".to_owned())),
+                "LOCAL" => return Err(error(self, "LOCAL outside of a macro".to_owned())),
+                "INCLUDE" => {
+                    let file = statement.operands.trim_matches(|c| c == '\'' || c == '"');
+                    let path = Path::new(&line.location.file)
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .join(file);
+                    let text = fs::read_to_string(&path).map_err(|err| {
+                        error(self, format!("can't read {}: {}", path.display(), err))
+                    })?;
+                    let included = macros::source_lines(&path.display().to_string(), &text);
+                    self.list(line, Line::Bytes(Vec::new()));
+                    self.expand(
+                        ExpansionKind::Include(file.to_owned()),
+                        &line.location,
+                        &included,
+                    )?;
+                    continue;
+                }
+                _ if self.macros.contains_key(&upper) => {
+                    if let Some(label) = statement.label {
+                        self.define(label, self.address, SymbolKind::Label)
+                            .map_err(|message| error(self, message))?;
+                    }
+                    let body = self
+                        .macro_body(&upper, statement.operands)
+                        .map_err(|message| error(self, message))?;
+                    self.list(line, Line::Bytes(Vec::new()));
+                    self.expand(ExpansionKind::Macro(upper), &line.location, &body)?;
+                    continue;
+                }
+                _ => {
+                    let start = self.address;
+                    let result = self
+                        .line(statement)
+                        .map_err(|message| error(self, message))?;
+                    self.list_at(line, start, result);
+                    continue;
+                }
+            }
+            self.list(line, Line::Bytes(Vec::new()));
+        }
+
+        match conditionals.pop() {
+            Some(conditional) if !self.ended => {
+                Err(self.error(&conditional.location, "IF without ENDIF".to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Assemble `lines` as part of an expansion
+    fn expand(
+        &mut self,
+        kind: ExpansionKind,
+        location: &SourceLocation,
+        lines: &[SourceLine],
+    ) -> Result<(), AssemblyError> {
+        if self.expansions.len() == MAX_DEPTH {
+            return Err(self.error(location, "macros are nested too deeply".to_owned()));
+        }
+        self.expansions.push(Expansion {
+            kind,
+            location: location.clone(),
+        });
+        self.block(lines)?;
+        self.expansions.pop();
+        Ok(())
+    }
+
+    /// The lines of the macro `name` with `arguments` substituted, and its
+    /// LOCAL labels given names of their own
+    fn macro_body(&mut self, name: &str, arguments: &str) -> Result<Vec<SourceLine>, String> {
+        let definition = &self.macros[name];
+        let arguments = macros::split_arguments(arguments);
+        if arguments.len() > definition.parameters.len() {
+            return Err(format!(
+                "{} takes {} arguments",
+                name,
+                definition.parameters.len()
+            ));
+        }
+
+        let mut values: Vec<(String, String)> = definition
+            .parameters
+            .iter()
+            .cloned()
+            .zip(
+                arguments
+                    .into_iter()
+                    .chain(std::iter::repeat(String::new())),
+            )
+            .collect();
+        let mut body = definition.body.clone();
+
+        // LOCAL lines come first
+        while let Some(line) = body.first() {
+            let (directive, names) = macros::words(&line.text);
+            if directive != "LOCAL" || names.is_empty() {
+                break;
+            }
+            let (_, names) = split_mnemonic(strip_comment(line.text.trim()));
+            for local in split_operands(names) {
+                self.locals += 1;
+                values.push((local.to_owned(), format!("??{:04}", self.locals)));
+            }
+            body.remove(0);
+        }
+
+        for line in &mut body {
+            line.text = macros::substitute(&line.text, &values);
+        }
+        Ok(body)
+    }
+
+    fn error(&self, location: &SourceLocation, message: String) -> AssemblyError {
+        AssemblyError {
+            location: location.clone(),
+            message,
+            expansions: self.expansions.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn list(&mut self, line: &SourceLine, result: Line) {
+        self.list_at(line, self.address, result);
+    }
+
+    fn list_at(&mut self, line: &SourceLine, start: u16, result: Line) {
+        if !self.final_pass {
+            return;
+        }
+        let (address, bytes) = match result {
+            Line::Bytes(bytes) => (start, bytes),
+            Line::Value(value) => (value, Vec::new()),
+        };
+        self.listing.push(ListingLine {
+            line: line.location.line,
+            depth: self.expansions.len(),
+            address,
+            bytes,
+            source: line.text.clone(),
+        });
+    }
+
+    /// Split a line into its label, mnemonic and operands
+    fn parse<'a>(&self, text: &'a str) -> Statement<'a> {
+        let code = strip_comment(text);
+        let mut rest = code.trim();
+
+        // A label, either with a colon or starting the line
+        let mut label = None;
+        let first = rest.split_whitespace().next().unwrap_or_default();
+        if let Some((name, _)) = first.split_once(':') {
+            label = Some(name);
+            rest = rest[name.len() + 1..].trim_start();
+        } else if !code.starts_with(char::is_whitespace)
+            && !is_keyword(first)
+            && !self.macros.contains_key(&first.to_ascii_uppercase())
+        {
+            label = Some(first);
+            rest = rest[first.len()..].trim_start();
+        }
+
+        let (mut mnemonic, mut operands) = split_mnemonic(rest);
+        // An indented `NAME EQU value` or `NAME MACRO`
+        let (next, after) = split_mnemonic(operands);
+        let names = ["EQU", "SET", "MACRO"]
+            .iter()
+            .any(|directive| next.eq_ignore_ascii_case(directive));
+        if label.is_none() && names {
+            label = Some(mnemonic);
+            (mnemonic, operands) = (next, after);
+        }
+
+        Statement {
+            label,
+            mnemonic,
+            operands,
+        }
+    }
+
+    fn finish(self) -> Assembly {
+        let origin = self.output.keys().next().copied().unwrap_or_default();
+        let mut bytes = Vec::new();
+        for (&address, &byte) in &self.output {
+            let offset = usize::from(address - origin);
+            bytes.resize(offset, 0);
+            bytes.push(byte);
+        }
+
+        let mut symbols = SymbolTable::new();
+        for symbol in self.symbols.values() {
+            if symbol.kind == SymbolKind::Label && symbols.name(symbol.value).is_none() {
+                symbols.insert(symbol.value, &symbol.name);
+            }
+        }
+
+        Assembly {
+            origin,
+            bytes,
+            entry: self.entry,
+            listing: self.listing,
+            symbols,
+        }
+    }
+
+    /// Assemble one line
+    fn line(&mut self, statement: Statement) -> Result<Line, String> {
+        let Statement {
+            label,
+            mnemonic,
+            operands,
+        } = statement;
+
+        // EQU and SET name a value rather than the address
+        let upper = mnemonic.to_ascii_uppercase();
+        if upper == "EQU" || upper == "SET" {
+            let Some(name) = label else {
+                return Err(format!("{} needs a name", upper));
+            };
+            let kind = if upper == "SET" {
+                SymbolKind::Set
+            } else {
+                SymbolKind::Equate
+            };
+            return match self.evaluate(operands) {
+                Ok(value) => {
+                    let value = word(value)?;
+                    self.define(name, value, kind)?;
+                    Ok(Line::Value(value))
+                }
+                // Defined in the final pass, once what it refers to is known
+                Err(Evaluation::Undefined(_)) if !self.final_pass => {
+                    if kind == SymbolKind::Equate {
+                        self.pending.insert(
+                            name.to_ascii_uppercase(),
+                            (operands.to_owned(), self.address),
+                        );
+                    }
+                    Ok(Line::Value(0))
+                }
+                Err(err) => Err(err.to_string()),
+            };
+        }
+
+        if let Some(label) = label {
+            self.define(label, self.address, SymbolKind::Label)?;
+        }
+        if mnemonic.is_empty() {
+            return Ok(Line::Bytes(Vec::new()));
+        }
+
+        let operands = split_operands(operands);
+        let bytes = match upper.as_str() {
+            "ORG" => {
+                let [address] = operands[..] else {
+                    return Err("ORG takes an address".to_owned());
+                };
+                self.address = word(self.evaluate_now(address)?)?;
+                self.past_end = false;
+                Vec::new()
+            }
+            "DB" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match string_literal(operand) {
+                        Some(text) => bytes.extend(text.bytes()),
+                        None => bytes.push(byte(self.evaluate_later(operand)?)?),
+                    }
+                }
+                bytes
+            }
+            "DW" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    bytes.extend(word(self.evaluate_later(operand)?)?.to_le_bytes());
+                }
+                bytes
+            }
+            "DS" => {
+                let [size] = operands[..] else {
+                    return Err("DS takes a size".to_owned());
+                };
+                let size = word(self.evaluate_now(size)?)?;
+                let (address, past_end) = self.address.overflowing_add(size);
+                self.address = address;
+                self.past_end |= past_end;
+                Vec::new()
+            }
+            "END" => {
+                if let [entry] = operands[..] {
+                    self.entry = Some(word(self.evaluate_later(entry)?)?);
+                }
+                self.ended = true;
+                Vec::new()
+            }
+            upper => {
+                let instruction = self.instruction(upper, &operands)?;
+                instruction.encode().map_err(|err| match err {
+                    Error::InvalidRegister(register) => {
+                        format!("{} can't be used with {}", register, upper)
+                    }
+                    err => err.to_string(),
+                })?
+            }
+        };
+
+        self.emit(&bytes)?;
+        Ok(Line::Bytes(bytes))
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        for &byte in bytes {
+            if self.past_end {
+                return Err("assembled past 0xffff".to_owned());
+            }
+            if self.final_pass && self.output.insert(self.address, byte).is_some() {
+                return Err(format!("{:#06x} has already been assembled", self.address));
+            }
+            let (address, past_end) = self.address.overflowing_add(1);
+            self.address = address;
+            self.past_end = past_end;
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: u16, kind: SymbolKind) -> Result<(), String> {
+        if !is_name(name) || is_keyword(name) {
+            return Err(format!("{} can't be used as a name", name));
+        }
+
+        let key = name.to_ascii_uppercase();
+        let redefinable = kind == SymbolKind::Set
+            && self
+                .symbols
+                .get(&key)
+                .is_none_or(|symbol| symbol.kind == SymbolKind::Set);
+        if !self.defined.insert(key.clone()) && !redefinable {
+            return Err(format!("{} is already defined", name));
+        }
+        self.symbols.insert(
+            key,
+            Symbol {
+                name: name.to_owned(),
+                value,
+                kind,
+            },
+        );
+        Ok(())
+    }
+
+    /// The instruction for `mnemonic`, which is upper case
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Instruction, String> {
+        use Instruction::*;
+
+        let Some(&(_, count)) = MNEMONICS.iter().find(|&&(name, _)| name == mnemonic) else {
+            return Err(format!("unknown instruction {}", mnemonic));
+        };
+        if operands.len() != count {
+            return Err(format!("{} takes {} operands", mnemonic, count));
+        }
+
+        let register = |index: usize| parse_register(operands[index]);
+        let data =
+            |index: usize| -> Result<u8, String> { byte(self.evaluate_later(operands[index])?) };
+        let address =
+            |index: usize| -> Result<u16, String> { word(self.evaluate_later(operands[index])?) };
+
+        Ok(match mnemonic {
+            "NOP" => NoOp,
+            "LXI" => LXI {
+                register: register(0)?,
+                value: address(1)?,
+            },
+            "STAX" => STAX {
+                register: register(0)?,
+            },
+            "INX" => INX {
+                register: register(0)?,
+            },
+            "INR" => INR {
+                register: register(0)?,
+            },
+            "DCR" => DCR {
+                register: register(0)?,
+            },
+            "MVI" => MVI {
+                register: register(0)?,
+                value: data(1)?,
+            },
+            "RLC" => RLC,
+            "DAD" => DAD {
+                register: register(0)?,
+            },
+            "LDAX" => LDAX {
+                register: register(0)?,
+            },
+            "DCX" => DCX {
+                register: register(0)?,
+            },
+            "RRC" => RRC,
+            "RAL" => RAL,
+            "RAR" => RAR,
+            "SHLD" => SHLD {
+                address: address(0)?,
+            },
+            "CMA" => CMA,
+            "DAA" => DAA,
+            "LHLD" => LHLD {
+                address: address(0)?,
+            },
+            "STA" => STA {
+                address: address(0)?,
+            },
+            "STC" => STC,
+            "LDA" => LDA {
+                address: address(0)?,
+            },
+            "CMC" => CMC,
+            "MOV" => MOV {
+                destination: register(0)?,
+                source: register(1)?,
+            },
+            "HLT" => HLT,
+            "ADD" => ADD {
+                register: register(0)?,
+            },
+            "ADC" => ADC {
+                register: register(0)?,
+            },
+            "SUB" => SUB {
+                register: register(0)?,
+            },
+            "SBB" => SBB {
+                register: register(0)?,
+            },
+            "ANA" => ANA {
+                register: register(0)?,
+            },
+            "XRA" => XRA {
+                register: register(0)?,
+            },
+            "ORA" => ORA {
+                register: register(0)?,
+            },
+            "CMP" => CMP {
+                register: register(0)?,
+            },
+            "RNZ" => RNZ,
+            "POP" => POP {
+                register: register(0)?,
+            },
+            "JNZ" => JNZ {
+                address: address(0)?,
+            },
+            "JMP" => JMP {
+                address: address(0)?,
+            },
+            "CNZ" => CNZ {
+                address: address(0)?,
+            },
+            "PUSH" => PUSH {
+                register: register(0)?,
+            },
+            "ADI" => ADI { data: data(0)? },
+            "RST" => {
+                let vector = self.evaluate_later(operands[0])?;
+                if !(0..8).contains(&vector) {
+                    return Err(format!("RST {} doesn't exist", vector));
+                }
+                RST { data: vector as u8 }
+            }
+            "RZ" => RZ,
+            "RET" => RET,
+            "JZ" => JZ {
+                address: address(0)?,
+            },
+            "CZ" => CZ {
+                address: address(0)?,
+            },
+            "CALL" => CALL {
+                address: address(0)?,
+            },
+            "ACI" => ACI { data: data(0)? },
+            "RNC" => RNC,
+            "JNC" => JNC {
+                address: address(0)?,
+            },
+            "OUT" => OUT { data: data(0)? },
+            "CNC" => CNC {
+                address: address(0)?,
+            },
+            "SUI" => SUI { data: data(0)? },
+            "RC" => RC,
+            "JC" => JC {
+                address: address(0)?,
+            },
+            "IN" => IN { data: data(0)? },
+            "CC" => CC {
+                address: address(0)?,
+            },
+            "SBI" => SBI { data: data(0)? },
+            "RPO" => RPO,
+            "JPO" => JPO {
+                address: address(0)?,
+            },
+            "XTHL" => XTHL,
+            "CPO" => CPO {
+                address: address(0)?,
+            },
+            "ANI" => ANI { data: data(0)? },
+            "RPE" => RPE,
+            "PCHL" => PCHL,
+            "JPE" => JPE {
+                address: address(0)?,
+            },
+            "XCHG" => XCHG,
+            "CPE" => CPE {
+                address: address(0)?,
+            },
+            "XRI" => XRI { data: data(0)? },
+            "RP" => RP,
+            "JP" => JP {
+                address: address(0)?,
+            },
+            "DI" => DI,
+            "CP" => CP {
+                address: address(0)?,
+            },
+            "ORI" => ORI { data: data(0)? },
+            "RM" => RM,
+            "SPHL" => SPHL,
+            "JM" => JM {
+                address: address(0)?,
+            },
+            "EI" => EI,
+            "CM" => CM {
+                address: address(0)?,
+            },
+            "CPI" => CPI { data: data(0)? },
+            _ => unreachable!("{} is in MNEMONICS", mnemonic),
+        })
+    }
+
+    /// Evaluate an expression whose value is needed in the first pass, like
+    /// the operand of ORG
+    fn evaluate_now(&self, expression: &str) -> Result<i64, String> {
+        self.evaluate(expression).map_err(|err| match err {
+            Evaluation::Undefined(name) => format!("{} must be defined before it's used", name),
+            err => err.to_string(),
+        })
+    }
+
+    /// Evaluate an expression that may refer to labels further on, which
+    /// are only known in the final pass
+    fn evaluate_later(&self, expression: &str) -> Result<i64, String> {
+        match self.evaluate(expression) {
+            Ok(value) => Ok(value),
+            Err(Evaluation::Undefined(_)) if !self.final_pass => Ok(0),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, Evaluation> {
+        self.evaluate_at(expression, self.address)
+    }
+
+    /// Evaluate `expression` with `$` as `address`
+    fn evaluate_at(&self, expression: &str, address: u16) -> Result<i64, Evaluation> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            address,
+            assembler: self,
+        };
+        let value = parser.or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(value),
+            Some(token) => Err(Evaluation::Syntax(format!("unexpected {}", token))),
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<i64, Evaluation> {
+        let key = name.to_ascii_uppercase();
+        // In the final pass, labels further on have their values from the
+        // first pass
+        let known = self.final_pass || self.defined.contains(&key);
+        if let Some(symbol) = self.symbols.get(&key).filter(|_| known) {
+            return Ok(symbol.value.into());
+        }
+
+        // An EQU further on that refers to another one further on
+        match self.pending.get(&key).filter(|_| self.final_pass) {
+            Some((expression, address)) => {
+                if !self.resolving.borrow_mut().insert(key.clone()) {
+                    return Err(Evaluation::Syntax(format!(
+                        "{} is defined in terms of itself",
+                        name
+                    )));
+                }
+                let value = self.evaluate_at(expression, *address);
+                self.resolving.borrow_mut().remove(&key);
+                Ok(word(value?).map_err(Evaluation::Syntax)?.into())
+            }
+            None => Err(Evaluation::Undefined(name.to_owned())),
+        }
+    }
+}
+
+/// What a line assembled to
+enum Line {
+    Bytes(Vec<u8>),
+    /// The value given to a name by EQU or SET
+    Value(u16),
+}
+
+/// Why an expression couldn't be evaluated
+#[derive(Debug)]
+enum Evaluation {
+    Undefined(String),
+    Syntax(String),
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Evaluation::Undefined(name) => write!(f, "{} isn't defined", name),
+            Evaluation::Syntax(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Dollar,
+    Symbol(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Dollar => write!(f, "$"),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, Evaluation> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut value = 0;
+            let mut length = 0;
+            loop {
+                match chars.next() {
+                    Some((_, quote)) if quote == c => {
+                        // A doubled quote stands for itself
+                        if chars.peek().map(|&(_, next)| next) != Some(c) {
+                            break;
+                        }
+                        chars.next();
+                        value = value << 8 | quote as i64;
+                    }
+                    Some((_, character)) => value = value << 8 | character as i64,
+                    None => return Err(Evaluation::Syntax("unterminated string".to_owned())),
+                }
+                length += 1;
+            }
+            if !(1..=2).contains(&length) {
+                return Err(Evaluation::Syntax(
+                    "a string in an expression must be 1 or 2 characters".to_owned(),
+                ));
+            }
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphanumeric() || "_?@.$".contains(c) {
+            let mut end = start;
+            while let Some(&(index, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || "_?@.$".contains(c)) {
+                    break;
+                }
+                end = index + c.len_utf8();
+                chars.next();
+            }
+            let word = &expression[start..end];
+            tokens.push(if word == "$" {
+                Token::Dollar
+            } else if word.starts_with(|c: char| c.is_ascii_digit() || c == '$') {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Name(word.to_owned())
+            });
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(Evaluation::Syntax(format!("unexpected {}", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<i64, Evaluation> {
+    let upper = word.to_ascii_uppercase();
+    let (digits, radix) = if let Some(digits) = upper.strip_prefix("0X") {
+        (digits, 16)
+    } else if let Some(digits) = upper.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = upper.strip_suffix('H') {
+        (digits, 16)
+    } else if let Some(digits) = upper.strip_suffix(['O', 'Q']) {
+        (digits, 8)
+    } else if let Some(digits) = upper.strip_suffix('B') {
+        (digits, 2)
+    } else if let Some(digits) = upper.strip_suffix('D') {
+        (digits, 10)
+    } else {
+        (upper.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix)
+        .map_err(|_| Evaluation::Syntax(format!("{} isn't a number", word)))
+}
+
+/// A recursive descent parser over expression tokens, from the lowest
+/// precedence operators to the highest
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    // The value of `$`
+    address: u16,
+    assembler: &'a Assembler,
+}
+
+impl Parser<'_> {
+    fn next_is(&mut self, operator: &str) -> bool {
+        let matches = match self.tokens.get(self.position) {
+            Some(Token::Name(name)) => name.eq_ignore_ascii_case(operator),
+            Some(Token::Symbol(symbol)) => operator.len() == 1 && operator.starts_with(*symbol),
+            _ => false,
+        };
+        if matches {
+            self.position += 1;
+        }
+        matches
+    }
+
+    fn or(&mut self) -> Result<i64, Evaluation> {
+        let mut value = self.and()?;
+        loop {
+            if self.next_is("OR") {
+                value |= self.and()?;
+            } else if self.next_is("XOR") {
+                value ^= self.and()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn and(&mut self) -> Result<i64, Evaluation> {
+        let mut value = self.not()?;
+        while self.next_is("AND") {
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<i64, Evaluation> {
+        if self.next_is("NOT") {
+            Ok(!self.not()?)
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<i64, Evaluation> {
+        let value = self.sum()?;
+        for operator in ["EQ", "NE", "LT", "LE", "GT", "GE"] {
+            if self.next_is(operator) {
+                let (left, right) = (value & 0xffff, self.sum()? & 0xffff);
+                let holds = match operator {
+                    "EQ" => left == right,
+                    "NE" => left != right,
+                    "LT" => left < right,
+                    "LE" => left <= right,
+                    "GT" => left > right,
+                    _ => left >= right,
+                };
+                // True is all ones
+                return Ok(if holds { 0xffff } else { 0 });
+            }
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<i64, Evaluation> {
+        let mut value = self.product()?;
+        loop {
+            if self.next_is("+") {
+                value = value.checked_add(self.product()?).ok_or_else(overflow)?;
+            } else if self.next_is("-") {
+                value = value.checked_sub(self.product()?).ok_or_else(overflow)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, Evaluation> {
+        let mut value = self.unary()?;
+        loop {
+            if self.next_is("*") {
+                value = value.checked_mul(self.unary()?).ok_or_else(overflow)?;
+            } else if self.next_is("/") || self.next_is("MOD") {
+                let divide = matches!(self.tokens[self.position - 1], Token::Symbol('/'));
+                let divisor = self.unary()?;
+                if divisor == 0 {
+                    return Err(Evaluation::Syntax("division by zero".to_owned()));
+                }
+                value = if divide {
+                    value.checked_div(divisor)
+                } else {
+                    value.checked_rem(divisor)
+                }
+                .ok_or_else(overflow)?;
+            } else if self.next_is("SHL") {
+                value <<= self.unary()? & 0x1f;
+            } else if self.next_is("SHR") {
+                value = (value & 0xffff) >> (self.unary()? & 0x1f);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, Evaluation> {
+        if self.next_is("-") {
+            self.unary()?.checked_neg().ok_or_else(overflow)
+        } else if self.next_is("+") {
+            self.unary()
+        } else if self.next_is("HIGH") {
+            Ok(self.unary()? >> 8 & 0xff)
+        } else if self.next_is("LOW") {
+            Ok(self.unary()? & 0xff)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, Evaluation> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Dollar) => Ok(self.address.into()),
+            Some(Token::Name(name)) => self.assembler.symbol(&name),
+            Some(Token::Symbol('(')) => {
+                let value = self.or()?;
+                if !self.next_is(")") {
+                    return Err(Evaluation::Syntax("missing )".to_owned()));
+                }
+                Ok(value)
+            }
+            Some(token) => Err(Evaluation::Syntax(format!("unexpected {}", token))),
+            None => Err(Evaluation::Syntax("expression ends early".to_owned())),
+        }
+    }
+}
+
+fn overflow() -> Evaluation {
+    Evaluation::Syntax("overflow".to_owned())
+}
+
+fn parse_register(operand: &str) -> Result<Reg, String> {
+    operand
+        .parse()
+        .map_err(|_| format!("{} isn't a register", operand))
+}
+
+/// Parse one instruction for `Instruction::from_str`
+pub(crate) fn parse_instruction(text: &str) -> Result<Instruction, String> {
+    let assembler = Assembler {
+        final_pass: true,
+        ..Assembler::default()
+    };
+    let (mnemonic, operands) = split_mnemonic(text.trim());
+    let upper = mnemonic.to_ascii_uppercase();
+
+    // Undocumented instructions as `Display` writes them, with the opcode
+    // in brackets where there's a choice
+    if let Some(documented) = upper.strip_prefix('*') {
+        let (operands, opcode) = match operands.rsplit_once('(') {
+            Some((operands, opcode)) => (operands.trim(), Some(opcode.trim_end_matches(')'))),
+            None => (operands, None),
+        };
+        let opcode = opcode
+            .map(|opcode| assembler.evaluate_later(opcode).and_then(byte))
+            .transpose()?;
+        let address = || assembler.evaluate_later(operands).and_then(word);
+        let instruction = match (documented, opcode) {
+            ("NOP", Some(opcode)) => Instruction::UndocumentedNoOp { opcode },
+            ("JMP", None) => Instruction::UndocumentedJMP {
+                address: address()?,
+            },
+            ("RET", None) => Instruction::UndocumentedRET,
+            ("CALL", Some(opcode)) => Instruction::UndocumentedCALL {
+                opcode,
+                address: address()?,
+            },
+            _ => return Err(format!("unknown instruction {}", mnemonic)),
+        };
+        return match instruction.encode() {
+            Ok(_) => Ok(instruction),
+            Err(Error::InvalidAlias(opcode)) => {
+                Err(format!("{:#04x} isn't an alias of {}", opcode, documented))
+            }
+            Err(err) => Err(err.to_string()),
+        };
+    }
+
+    // `Display` separates two operands with a space, not a comma
+    let two_operands = MNEMONICS.contains(&(upper.as_str(), 2));
+    let operands = if two_operands && !operands.contains(',') {
+        let (first, second) = split_mnemonic(operands);
+        vec![first, second]
+    } else {
+        split_operands(operands)
+    };
+
+    let instruction = assembler.instruction(&upper, &operands)?;
+    instruction
+        .encode()
+        .map_err(|_| format!("{} isn't a valid instruction", text.trim()))?;
+    Ok(instruction)
+}
+
+/// A value that has to fit in a byte, signed or unsigned
+fn byte(value: i64) -> Result<u8, String> {
+    if (-0x80..=0xff).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} doesn't fit in a byte", value))
+    }
+}
+
+/// A value that has to fit in a word, signed or unsigned
+fn word(value: i64) -> Result<u16, String> {
+    if (-0x8000..=0xffff).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("{} doesn't fit in a word", value))
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || "_?@.".contains(c))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_?@.$".contains(c))
+}
+
+/// The contents of a quoted operand of more than one character, which DB
+/// stores as a string rather than evaluating
+fn string_literal(operand: &str) -> Option<String> {
+    let quote = operand.chars().next().filter(|&c| c == '\'' || c == '"')?;
+    let inner = operand.strip_prefix(quote)?.strip_suffix(quote)?;
+    let doubled = format!("{}{}", quote, quote);
+    // A quote on its own inside means this is an expression like 'A'+'B'
+    if inner.replace(&doubled, "").contains(quote) {
+        return None;
+    }
+    let text = inner.replace(&doubled, &quote.to_string());
+    Some(text).filter(|text| text.chars().count() != 1)
+}
+
+/// The first word of `text` and the rest of it
+fn split_mnemonic(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// The line without its comment
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => return &line[..index],
+            None => {}
+        }
+    }
+    line
+}
+
+/// Split operands on the commas that aren't in strings
+fn split_operands(operands: &str) -> Vec<&str> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut split = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in operands.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ',' => {
+                split.push(operands[start..index].trim());
+                start = index + 1;
+            }
+            None => {}
+        }
+    }
+    split.push(operands[start..].trim());
+    split
+}
@@ -3,15 +3,87 @@ use std::fs;
 fn main() -> Result<(), eighty_eighty::Error> {
     println!("Executable!");
 
-    let source = std::env::args().nth(1).expect("no source file given");
-    let contents = fs::read(source).expect("failed to open source");
+    // `--load <file>@<address>` and `--entry <address>` can go anywhere, and
+    // the source file is optional if something else is loaded
+    let mut image = eighty_eighty::Image::new();
+    let mut rest = Vec::new();
+    let mut all_args = std::env::args().skip(1);
+    while let Some(arg) = all_args.next() {
+        match arg.as_str() {
+            "--load" => {
+                let load = all_args.next().expect("--load needs <file>@<address>");
+                let (path, address) = load
+                    .rsplit_once('@')
+                    .expect("--load needs <file>@<address>");
+                let bytes = fs::read(path).expect("failed to open file to load");
+                image.add_segment(parse_address(address), bytes)?;
+            }
+            "--entry" => {
+                let entry = all_args.next().expect("--entry needs an address");
+                image.set_entry(parse_address(&entry));
+            }
+            _ => rest.push(arg),
+        }
+    }
+
+    let mut args = rest.into_iter().peekable();
+    let source = args.next_if(|arg| !arg.starts_with("--"));
+    let contents = match &source {
+        Some(source) => fs::read(source).expect("failed to open source"),
+        None if image.segments().is_empty() => panic!("no source file given"),
+        None => Vec::new(),
+    };
 
-    let mut cpu = eighty_eighty::Cpu::new(|_| {});
-    cpu.load_into_memory(contents)?;
+    let option = args.next();
 
-    while !cpu.halted() {
-        cpu.step()?;
+    // `--disassemble [symbol file]` lists the program instead of running it,
+    // following its control flow from the reset and RST vectors and any
+    // `--entry`. Only the source file is disassembled
+    if option.as_deref() == Some("--disassemble") {
+        let mut symbols = match args.next() {
+            Some(path) => {
+                let text = fs::read_to_string(path).expect("failed to open symbol file");
+                eighty_eighty::SymbolTable::parse(&text)?
+            }
+            None => eighty_eighty::SymbolTable::new(),
+        };
+        let analysis = eighty_eighty::analyze(&contents, 0, image.entry().as_slice());
+        symbols.add_generated_labels(&analysis);
+        for line in analysis.lines() {
+            println!("{}", line.display_with(&symbols));
+        }
+        for address in analysis.unresolved_jumps() {
+            eprintln!("Unresolved PCHL at {:#06x}", address);
+        }
+        return Ok(());
     }
 
+    // The source file goes at 0x0000, as well as any other segments
+    if source.is_some() {
+        image.add_segment(0, contents)?;
+    }
+    let mut cpu = eighty_eighty::Cpu::new(|_, _| {});
+    cpu.load_image(&image);
+
+    // `--gdb <address>` waits for GDB to attach instead of running
+    if option.as_deref() == Some("--gdb") {
+        let address = args.next().unwrap_or_else(|| "localhost:1234".to_owned());
+        println!("Waiting for GDB on {}", address);
+        eighty_eighty::GdbServer::new(cpu)
+            .listen(address)
+            .expect("GDB session failed");
+        return Ok(());
+    }
+
+    let mut debugger = eighty_eighty::Debugger::new();
+    let reason = debugger.run(&mut cpu)?;
+    println!("Stopped: {}", debugger.describe(&reason));
+
     Ok(())
 }
+
+/// Hex, with or without a `0x` prefix
+fn parse_address(text: &str) -> u16 {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u16::from_str_radix(digits, 16).expect("invalid address")
+}
@@ -1,11 +1,78 @@
-use crate::{instruction::Reg, Instruction};
-
-#[derive(Debug)]
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    instruction::{Condition, Reg},
+    journal::{Journal, Registers},
+    memory::{AccessKind, MemoryAccess, MEMORY_SIZE},
+    save_state::{ROW_SIZE, SAVE_STATE_VERSION},
+    Flags, Image, Instruction, IntelHex, IntelHexError, Io, Memory, MemoryBus, RegisterState,
+    SaveState, TraceRecord, TraceSink,
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     OutOfMemory,
     BadMemoryAccess(u16),
+    /// The register can't be used by the operation, e.g. `Reg::A` as a
+    /// register pair
+    InvalidRegister(Reg),
+    /// A push would move the stack pointer below the limit set with
+    /// `Cpu::set_stack_limit`
+    StackOverflow {
+        sp: u16,
+    },
+    /// The program wrote to ROM on a bus set up to report it
+    WriteToRom(u16),
+    /// A save state couldn't be decoded
+    InvalidSaveState(&'static str),
+    /// A line of a symbol file isn't a name and an address
+    InvalidSymbolFile {
+        line: usize,
+    },
+    /// An undocumented instruction was given an opcode that isn't one of
+    /// its aliases, so it can't be encoded
+    InvalidAlias(u8),
+    /// Text couldn't be parsed as an instruction or register
+    InvalidSyntax(String),
+    InvalidIntelHex {
+        line: usize,
+        error: IntelHexError,
+    },
+    /// A segment added to an `Image` overlaps another one, starting at
+    /// `address`
+    OverlappingSegments {
+        address: u16,
+    },
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::OutOfMemory => write!(f, "program doesn't fit in memory"),
+            Error::BadMemoryAccess(address) => {
+                write!(f, "bad memory access at {:#06x}", address)
+            }
+            Error::InvalidRegister(register) => write!(f, "invalid register {}", register),
+            Error::StackOverflow { sp } => write!(f, "stack overflow with sp at {:#06x}", sp),
+            Error::WriteToRom(address) => write!(f, "write to ROM at {:#06x}", address),
+            Error::InvalidSaveState(reason) => write!(f, "invalid save state: {}", reason),
+            Error::InvalidSymbolFile { line } => write!(f, "invalid symbol on line {}", line),
+            Error::InvalidAlias(opcode) => {
+                write!(f, "{:#04x} isn't an undocumented alias", opcode)
+            }
+            Error::InvalidSyntax(message) => write!(f, "invalid syntax: {}", message),
+            Error::InvalidIntelHex { line, error } => {
+                write!(f, "invalid Intel HEX on line {}: {}", line, error)
+            }
+            Error::OverlappingSegments { address } => {
+                write!(f, "segments overlap at {:#06x}", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) struct ConditionCodes {
     // Zero Flag
@@ -30,14 +97,30 @@ impl ConditionCodes {
             ac: 0,
         }
     }
-}
 
-const MEMORY_SIZE: usize = 65_536;
+    /// Whether the given condition currently holds
+    pub(crate) fn check(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::NotZero => self.z == 0,
+            Condition::Zero => self.z != 0,
+            Condition::NoCarry => self.cy == 0,
+            Condition::Carry => self.cy != 0,
+            Condition::ParityOdd => self.p == 0,
+            Condition::ParityEven => self.p != 0,
+            Condition::Plus => self.s == 0,
+            Condition::Minus => self.s != 0,
+        }
+    }
+}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Cpu<BusWriteCallBack>
+/// Cloning and comparing a CPU only looks at the state of the machine, so a
+/// clone starts with an empty journal and equal machines compare equal
+/// whatever their history
+#[derive(Debug)]
+pub struct Cpu<IO, M = Memory>
 where
-    BusWriteCallBack: FnMut(u8),
+    IO: Io,
+    M: MemoryBus,
 {
     pub(crate) a: u8,
     pub(crate) b: u8,
@@ -48,15 +131,83 @@ where
     pub(crate) l: u8,
     pub(crate) pc: u16,
     pub(crate) sp: u16,
-    pub(crate) memory: [u8; MEMORY_SIZE],
+    pub(crate) memory: M,
     pub(crate) condition_codes: ConditionCodes,
-    int_enable: u8,
-    on_bus_write: BusWriteCallBack,
+    // The INTE flip-flop
+    int_enable: bool,
+    // Set by EI so that interrupts are only accepted after the following
+    // instruction has executed
+    interrupt_delay: bool,
+    // Instructions waiting to be placed on the data bus by an interrupting
+    // device, oldest first
+    pending_interrupts: VecDeque<Instruction>,
+    // Pushes may not move sp below this address
+    stack_limit: Option<u16>,
+    // Clock cycles (T-states) executed since the CPU was created
+    cycles: u64,
+    journal: Journal,
+    // Data reads and writes made by the last step, for watchpoints
+    memory_accesses: Vec<MemoryAccess>,
+    io: IO,
     bus: u8,
     halted: bool,
 }
 
-impl<T: FnMut(u8)> std::fmt::Display for Cpu<T> {
+impl<IO: Io + Clone, M: MemoryBus + Clone> Clone for Cpu<IO, M> {
+    fn clone(&self) -> Self {
+        let mut journal = Journal::default();
+        journal.set_limit(self.journal.limit());
+        Self {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            pc: self.pc,
+            sp: self.sp,
+            memory: self.memory.clone(),
+            condition_codes: self.condition_codes,
+            int_enable: self.int_enable,
+            interrupt_delay: self.interrupt_delay,
+            pending_interrupts: self.pending_interrupts.clone(),
+            stack_limit: self.stack_limit,
+            cycles: self.cycles,
+            journal,
+            memory_accesses: Vec::new(),
+            io: self.io.clone(),
+            bus: self.bus,
+            halted: self.halted,
+        }
+    }
+}
+
+impl<IO: Io + PartialEq, M: MemoryBus + PartialEq> PartialEq for Cpu<IO, M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.a == other.a
+            && self.b == other.b
+            && self.c == other.c
+            && self.d == other.d
+            && self.e == other.e
+            && self.h == other.h
+            && self.l == other.l
+            && self.pc == other.pc
+            && self.sp == other.sp
+            && self.memory == other.memory
+            && self.condition_codes == other.condition_codes
+            && self.int_enable == other.int_enable
+            && self.interrupt_delay == other.interrupt_delay
+            && self.pending_interrupts == other.pending_interrupts
+            && self.stack_limit == other.stack_limit
+            && self.cycles == other.cycles
+            && self.io == other.io
+            && self.bus == other.bus
+            && self.halted == other.halted
+    }
+}
+
+impl<IO: Io, M: MemoryBus> std::fmt::Display for Cpu<IO, M> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -66,8 +217,17 @@ impl<T: FnMut(u8)> std::fmt::Display for Cpu<T> {
     }
 }
 
-impl<T: FnMut(u8)> Cpu<T> {
-    pub fn new(on_bus_write: T) -> Self {
+impl<IO: Io> Cpu<IO> {
+    /// Create a CPU with its ports connected to `io` and a flat 64 KiB of RAM
+    pub fn new(io: IO) -> Self {
+        Self::with_memory(io, Memory::new())
+    }
+}
+
+impl<IO: Io, M: MemoryBus> Cpu<IO, M> {
+    /// Create a CPU with its ports connected to `io` and its address space
+    /// provided by `memory`
+    pub fn with_memory(io: IO, memory: M) -> Self {
         Self {
             a: 0,
             b: 0,
@@ -78,19 +238,29 @@ impl<T: FnMut(u8)> Cpu<T> {
             l: 0,
             pc: 0,
             sp: 0,
-            memory: [0; MEMORY_SIZE],
+            memory,
             condition_codes: ConditionCodes::new(),
-            int_enable: 1,
-            on_bus_write,
+            int_enable: false,
+            interrupt_delay: false,
+            pending_interrupts: VecDeque::new(),
+            stack_limit: None,
+            cycles: 0,
+            journal: Journal::default(),
+            memory_accesses: Vec::new(),
+            io,
             bus: 0,
             halted: false,
         }
     }
 
-    pub fn memory(&self) -> &[u8] {
+    pub fn memory(&self) -> &M {
         &self.memory
     }
 
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
     pub fn a(&self) -> u8 {
         self.a
     }
@@ -123,6 +293,11 @@ impl<T: FnMut(u8)> Cpu<T> {
         self.pc
     }
 
+    /// Jump to `pc`, as when starting a program
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
     pub fn sp(&self) -> u16 {
         self.sp
     }
@@ -131,132 +306,477 @@ impl<T: FnMut(u8)> Cpu<T> {
         self.halted
     }
 
+    /// The number of clock cycles (T-states) executed so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Whether the INTE flip-flop is set, i.e. interrupts will be accepted
+    pub fn interrupts_enabled(&self) -> bool {
+        self.int_enable
+    }
+
+    /// The interrupt requests that haven't been acknowledged yet
+    pub fn pending_interrupts(&self) -> impl Iterator<Item = &Instruction> {
+        self.pending_interrupts.iter()
+    }
+
+    /// Latch a value onto the data bus. Reads from ports that `IO` doesn't
+    /// drive return this value
     pub fn write_to_bus(&mut self, value: u8) {
         self.bus = value;
     }
 
-    pub fn generate_interrupt(&mut self, value: u8) -> Result<(), Error> {
-        if self.int_enable == 9 || self.halted {
-            return Ok(());
+    /// Make pushes (including CALL, RST and interrupts) that would move the
+    /// stack pointer below `limit` fail with `Error::StackOverflow`. The 8080
+    /// itself just wraps around, which is what happens with no limit set
+    pub fn set_stack_limit(&mut self, limit: Option<u16>) {
+        self.stack_limit = limit;
+    }
+
+    /// Request an interrupt that places `instruction` on the data bus. This
+    /// is usually an RST, but the 8080 will execute any instruction supplied
+    /// during the interrupt acknowledge cycle
+    ///
+    /// Requests are queued until interrupts are enabled, and each one is
+    /// acknowledged at the start of a subsequent `step`
+    pub fn request_interrupt(&mut self, instruction: Instruction) {
+        self.pending_interrupts.push_back(instruction);
+    }
+
+    /// Request an interrupt that executes `RST value`
+    pub fn generate_interrupt(&mut self, value: u8) {
+        self.request_interrupt(Instruction::RST { data: value });
+    }
+
+    /// The interrupt request the next step will acknowledge, if any
+    fn interrupt_ready(&self) -> Option<&Instruction> {
+        if !self.int_enable || self.interrupt_delay {
+            return None;
         }
+        self.pending_interrupts.front()
+    }
 
-        let instruction = Instruction::RST { data: value };
+    /// Execute an interrupting instruction, if interrupts are enabled and
+    /// one is pending. Returns whether an interrupt was acknowledged
+    fn acknowledge_interrupt(&mut self) -> Result<bool, Error> {
+        if self.interrupt_ready().is_none() {
+            return Ok(false);
+        }
+        let Some(instruction) = self.pending_interrupts.pop_front() else {
+            return Ok(false);
+        };
+        self.journal.record_interrupt(instruction);
 
-        self.execute_instruction(instruction)?;
-        self.pc += instruction.op_bytes() as u16;
+        // Acknowledging an interrupt resets INTE and resumes a halted CPU.
+        // The pc isn't advanced for the instruction on the data bus, so an
+        // RST or CALL pushes the address of the next instruction of the
+        // interrupted program
+        self.int_enable = false;
+        self.halted = false;
+        self.cycles += u64::from(self.execute_instruction(instruction)?);
 
-        Ok(())
+        Ok(true)
     }
 
-    pub(crate) fn assign_value(&mut self, reg: Reg, val: u8) {
+    pub(crate) fn assign_value(&mut self, reg: Reg, val: u8) -> Result<(), Error> {
         match reg {
             Reg::A => self.a = val,
             Reg::B => self.b = val,
             Reg::C => self.c = val,
             Reg::D => self.d = val,
             Reg::E => self.e = val,
-            Reg::SP => todo!(),
             Reg::H => self.h = val,
             Reg::L => self.l = val,
-            Reg::Psw => todo!(),
-            Reg::M => todo!(),
+            Reg::SP | Reg::Psw | Reg::M => return Err(Error::InvalidRegister(reg)),
         }
+        Ok(())
     }
 
-    pub(crate) fn get_register_val(&self, reg: Reg) -> u8 {
+    pub(crate) fn get_register_val(&self, reg: Reg) -> Result<u8, Error> {
         match reg {
-            Reg::A => self.a,
-            Reg::B => self.b,
-            Reg::C => self.c,
-            Reg::D => self.d,
-            Reg::E => self.e,
-            Reg::SP => todo!(),
-            Reg::H => self.h,
-            Reg::L => self.l,
-            Reg::Psw => todo!(),
-            Reg::M => todo!(),
+            Reg::A => Ok(self.a),
+            Reg::B => Ok(self.b),
+            Reg::C => Ok(self.c),
+            Reg::D => Ok(self.d),
+            Reg::E => Ok(self.e),
+            Reg::H => Ok(self.h),
+            Reg::L => Ok(self.l),
+            Reg::SP | Reg::Psw | Reg::M => Err(Error::InvalidRegister(reg)),
         }
     }
 
     pub fn load_into_memory(&mut self, data: Vec<u8>) -> Result<(), Error> {
-        let data_len = data.len();
-
-        if data_len > self.memory.len() {
+        if data.len() > MEMORY_SIZE {
             return Err(Error::OutOfMemory);
         }
 
-        for (&element, memory_ptr) in data.iter().zip(self.memory.iter_mut()) {
-            *memory_ptr = element;
+        // Loading bypasses write protection so that ROM can be filled
+        for (address, &element) in data.iter().enumerate() {
+            self.memory.poke(address as u16, element);
+        }
+
+        Ok(())
+    }
+
+    /// Load an Intel HEX file, bypassing write protection, and move the pc
+    /// to its start address if it has one
+    pub fn load_intel_hex(&mut self, text: &str) -> Result<(), Error> {
+        let hex = IntelHex::parse(text)?;
+        for (start, bytes) in &hex.data {
+            for (offset, &byte) in bytes.iter().enumerate() {
+                self.memory.poke(start.wrapping_add(offset as u16), byte);
+            }
+        }
+        if let Some(start) = hex.start {
+            self.pc = start;
+        }
+        Ok(())
+    }
+
+    /// Load each segment of `image`, bypassing write protection, and move
+    /// the pc to its entry point if it has one
+    pub fn load_image(&mut self, image: &Image) {
+        for segment in image.segments() {
+            for (offset, &byte) in segment.bytes.iter().enumerate() {
+                self.memory
+                    .poke(segment.address.wrapping_add(offset as u16), byte);
+            }
+        }
+        if let Some(entry) = image.entry() {
+            self.pc = entry;
         }
+    }
 
+    /// Capture the state of the machine
+    ///
+    /// Only memory that `MemoryBus::is_storage` accepts is captured, and
+    /// the rest saves as 0
+    pub fn snapshot(&self) -> SaveState {
+        let mut memory = BTreeMap::new();
+        for address in (0..MEMORY_SIZE).step_by(ROW_SIZE) {
+            let address = address as u16;
+            let row: [u8; ROW_SIZE] = std::array::from_fn(|offset| {
+                let address = address + offset as u16;
+                if self.memory.is_storage(address) {
+                    self.memory.peek(address)
+                } else {
+                    0
+                }
+            });
+            if row != [0; ROW_SIZE] {
+                memory.insert(address, row);
+            }
+        }
+
+        SaveState {
+            version: SAVE_STATE_VERSION,
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            pc: self.pc,
+            sp: self.sp,
+            flags: Flags {
+                zero: self.condition_codes.z != 0,
+                sign: self.condition_codes.s != 0,
+                parity: self.condition_codes.p != 0,
+                carry: self.condition_codes.cy != 0,
+                auxiliary_carry: self.condition_codes.ac != 0,
+            },
+            interrupts_enabled: self.int_enable,
+            interrupt_delay: self.interrupt_delay,
+            halted: self.halted,
+            bus: self.bus,
+            cycles: self.cycles,
+            memory,
+        }
+    }
+
+    /// Return the machine to a captured state. Memory is written with
+    /// `MemoryBus::poke`, skipping devices and mirrors, and pending interrupt
+    /// requests are dropped
+    pub fn restore(&mut self, state: &SaveState) {
+        self.a = state.a;
+        self.b = state.b;
+        self.c = state.c;
+        self.d = state.d;
+        self.e = state.e;
+        self.h = state.h;
+        self.l = state.l;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.condition_codes = ConditionCodes {
+            z: state.flags.zero.into(),
+            s: state.flags.sign.into(),
+            p: state.flags.parity.into(),
+            cy: state.flags.carry.into(),
+            ac: state.flags.auxiliary_carry.into(),
+        };
+        self.int_enable = state.interrupts_enabled;
+        self.interrupt_delay = state.interrupt_delay;
+        self.pending_interrupts.clear();
+        self.halted = state.halted;
+        self.bus = state.bus;
+        self.cycles = state.cycles;
+        self.journal.clear();
+
+        for address in (0..MEMORY_SIZE).step_by(ROW_SIZE) {
+            let address = address as u16;
+            let row = state.memory.get(&address).unwrap_or(&[0; ROW_SIZE]);
+            for (offset, &value) in row.iter().enumerate() {
+                let address = address + offset as u16;
+                if self.memory.is_storage(address) {
+                    self.memory.poke(address, value);
+                }
+            }
+        }
+    }
+
+    /// Encode the state of the machine in the binary save state format
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().to_bytes()
+    }
+
+    /// Restore a state saved with `save_state`, possibly by an older version
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.restore(&SaveState::from_bytes(bytes)?);
         Ok(())
     }
 
+    /// Keep what's needed to undo up to `limit` steps, dropping the oldest
+    /// beyond that. The default limit of 0 turns the journal off
+    pub fn set_journal_limit(&mut self, limit: usize) {
+        self.journal.set_limit(limit);
+    }
+
+    /// The number of steps that can currently be undone
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+
+    /// Undo the most recent step, returning false if the journal is empty
+    ///
+    /// Memory is restored with `MemoryBus::poke`. Port output and any other
+    /// effects outside the CPU and its memory can't be undone
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.journal.pop() else {
+            return false;
+        };
+
+        for &(address, value) in entry.writes.iter().rev() {
+            self.memory.poke(address, value);
+        }
+        if let Some(interrupt) = entry.interrupt {
+            self.pending_interrupts.push_front(interrupt);
+        }
+        self.restore_registers(entry.registers);
+
+        true
+    }
+
+    /// Step back until the pc reaches `pc`, undoing at least one step.
+    /// Returns false if the journal ran out first
+    pub fn reverse_continue_to(&mut self, pc: u16) -> bool {
+        while self.step_back() {
+            if self.pc == pc {
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn step(&mut self) -> Result<(), Error> {
+        self.memory_accesses.clear();
+        if self.journal.limit() == 0 {
+            return self.execute_step();
+        }
+
+        self.journal.begin(self.registers());
+        // A step that fails part way is still journalled, so that whatever
+        // it did before failing can be undone
+        let result = self.execute_step();
+        self.journal.end();
+        result
+    }
+
+    fn execute_step(&mut self) -> Result<(), Error> {
+        if self.acknowledge_interrupt()? {
+            return Ok(());
+        }
+        self.interrupt_delay = false;
+
         if self.halted {
             return Ok(());
         };
-        if let Some(instruction) = self.fetch_instruction() {
-            self.execute_instruction(instruction)?;
-            self.pc += instruction.op_bytes() as u16;
-        }
+        let instruction = self.fetch_instruction();
+        // Like the real 8080, the pc is advanced past the instruction before
+        // it executes, so branches simply overwrite it
+        self.pc = self.pc.wrapping_add(instruction.op_bytes().into());
+        self.cycles += u64::from(self.execute_instruction(instruction)?);
+        Ok(())
+    }
+
+    /// Step, sending a record of what the instruction did to `sink`. Steps
+    /// where a halted CPU idles aren't recorded
+    pub fn step_traced(&mut self, sink: &mut impl TraceSink) -> Result<(), Error> {
+        let Some(instruction) = self.next_instruction()? else {
+            return self.step();
+        };
+        let bytes = if self.interrupt_ready().is_some() {
+            Vec::new()
+        } else {
+            (0..instruction.op_bytes())
+                .map(|offset| self.memory.peek(self.pc.wrapping_add(offset.into())))
+                .collect()
+        };
+        let before = self.register_state();
+        let cycles = self.cycles;
+
+        self.step()?;
+
+        sink.record(&TraceRecord {
+            pc: before.pc,
+            bytes,
+            instruction,
+            before,
+            after: self.register_state(),
+            memory_accesses: self.memory_accesses.clone(),
+            cycles,
+            instruction_cycles: (self.cycles - cycles) as u8,
+        });
         Ok(())
     }
 
-    pub(crate) fn fetch_instruction(&self) -> Option<Instruction> {
-        Instruction::decode(&self.memory[self.pc.into()..])
+    /// The programmer visible registers
+    pub fn register_state(&self) -> RegisterState {
+        RegisterState {
+            a: self.a,
+            flags: self.processor_status_word(),
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    /// Step until at least `budget` more clock cycles have elapsed, returning
+    /// how many actually did. This can overshoot by up to one instruction
+    ///
+    /// A halted CPU that can't be woken by a pending interrupt spends the
+    /// rest of the budget idling
+    pub fn run_for(&mut self, budget: u64) -> Result<u64, Error> {
+        let start = self.cycles;
+        let end = start.saturating_add(budget);
+
+        while self.cycles < end {
+            let before = self.cycles;
+            self.step()?;
+            if self.halted && self.cycles == before {
+                self.cycles = end;
+            }
+        }
+
+        Ok(self.cycles - start)
+    }
+
+    pub(crate) fn fetch_instruction(&mut self) -> Instruction {
+        decode_from(self.pc, |address| self.memory.read(address))
     }
 
-    pub(crate) fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), Error> {
+    /// The instruction the next `step` will execute: either an interrupt
+    /// that's about to be acknowledged or the one at the pc. `None` if the
+    /// CPU is halted and will stay that way
+    ///
+    /// Memory is read with `MemoryBus::peek`, so devices aren't disturbed
+    pub fn next_instruction(&self) -> Result<Option<Instruction>, Error> {
+        if let Some(&instruction) = self.interrupt_ready() {
+            return Ok(Some(instruction));
+        }
+        if self.halted {
+            return Ok(None);
+        }
+        Ok(Some(decode_from(self.pc, |address| {
+            self.memory.peek(address)
+        })))
+    }
+
+    /// The memory reads and writes made by the instructions of the last
+    /// `step`, in order. Instruction fetches aren't included
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.memory_accesses
+    }
+
+    /// Execute `instruction`, returning the number of clock cycles it took
+    pub(crate) fn execute_instruction(&mut self, instruction: Instruction) -> Result<u8, Error> {
+        // Branch instructions don't change the condition codes, so they can
+        // be tested up front
+        let cycles = match instruction.condition() {
+            Some(condition) if self.condition_codes.check(condition) => instruction.cycles_taken(),
+            _ => instruction.cycles(),
+        };
+
         match instruction {
             Instruction::NoOp => (),
             Instruction::LXI { register, value } => {
-                self.set_register_pair(register, value);
+                self.set_register_pair(register, value)?;
             }
             Instruction::STAX { register } => {
                 let val = self.a;
-                let address = self.load_register_pair(register);
+                let address = self.load_register_pair(register)?;
                 self.write_to_memory_at(address, val)?;
             }
             Instruction::INX { register } => {
-                let val = self.load_register_pair(register).wrapping_add(1);
-                self.set_register_pair(register, val);
+                let val = self.load_register_pair(register)?.wrapping_add(1);
+                self.set_register_pair(register, val)?;
             }
             Instruction::INR { register } => {
                 let res = if register == Reg::M {
                     let res = self.load_from_memory()?.wrapping_add(1);
-                    self.write_to_memory(res);
+                    self.write_to_memory(res)?;
                     res
                 } else {
-                    let res = self.get_register_val(register).wrapping_add(1);
-                    self.assign_value(register, res);
+                    let res = self.get_register_val(register)?.wrapping_add(1);
+                    self.assign_value(register, res)?;
                     res
                 };
                 // We don't update cy
                 self.condition_codes.z = (res == 0).into();
                 self.condition_codes.s = ((res & 0x80) != 0).into();
                 self.update_parity(res);
+                // Carry out of bit 3 only happens when the low nibble wraps to 0
+                self.condition_codes.ac = ((res & 0x0f) == 0x00).into();
             }
             Instruction::DCR { register } => {
                 let res = if register == Reg::M {
                     let res = self.load_from_memory()?.wrapping_sub(1);
-                    self.write_to_memory(res);
+                    self.write_to_memory(res)?;
                     res
                 } else {
-                    let res = self.get_register_val(register).wrapping_sub(1);
-                    self.assign_value(register, res);
+                    let res = self.get_register_val(register)?.wrapping_sub(1);
+                    self.assign_value(register, res)?;
                     res
                 };
                 // We don't update cy
                 self.condition_codes.z = (res == 0).into();
                 self.condition_codes.s = ((res & 0x80) != 0).into();
                 self.update_parity(res);
+                // DCR adds 0xff, which carries out of bit 3 unless the low
+                // nibble was 0
+                self.condition_codes.ac = ((res & 0x0f) != 0x0f).into();
             }
             Instruction::MVI { register, value } => {
                 if register == Reg::M {
-                    self.write_to_memory(value);
+                    self.write_to_memory(value)?;
                 } else {
-                    self.assign_value(register, value);
+                    self.assign_value(register, value)?;
                 }
             }
             Instruction::RLC => {
@@ -264,220 +784,183 @@ impl<T: FnMut(u8)> Cpu<T> {
                 self.condition_codes.cy = self.a & 1;
             }
             Instruction::DAD { register } => {
-                let result = self.load_register_pair(Reg::H) as u32
-                    + self.load_register_pair(register) as u32;
+                let result = self.load_register_pair(Reg::H)? as u32
+                    + self.load_register_pair(register)? as u32;
 
-                self.set_register_pair(Reg::H, (result & 0xffff) as u16);
+                self.set_register_pair(Reg::H, (result & 0xffff) as u16)?;
 
                 self.condition_codes.cy = if result > u16::MAX.into() { 1 } else { 0 };
             }
             Instruction::LDAX { register } => {
                 // (A) <- ((rp))
-                let address = self.load_register_pair(register);
+                let address = self.load_register_pair(register)?;
                 self.a = self.load_from_memory_at(address)?;
             }
             Instruction::DCX { register } => {
-                let val = self.load_register_pair(register).wrapping_sub(1);
-                self.set_register_pair(register, val);
+                let val = self.load_register_pair(register)?.wrapping_sub(1);
+                self.set_register_pair(register, val)?;
             }
             Instruction::RRC => {
                 self.condition_codes.cy = self.a & 1;
                 self.a = self.a.rotate_right(1);
             }
-            Instruction::RAL => todo!(),
-            Instruction::RAR => todo!(),
-            Instruction::SHLD { address } => todo!("{}", address),
-            Instruction::CMA => todo!(),
-            Instruction::DAA => todo!(),
-            Instruction::LHLD { address } => todo!("{}", address),
+            Instruction::RAL => {
+                let carry = self.condition_codes.cy;
+                self.condition_codes.cy = self.a >> 7;
+                self.a = (self.a << 1) | carry;
+            }
+            Instruction::RAR => {
+                let carry = self.condition_codes.cy;
+                self.condition_codes.cy = self.a & 1;
+                self.a = (self.a >> 1) | (carry << 7);
+            }
+            Instruction::SHLD { address } => {
+                // ((address)) <- (L); ((address + 1)) <- (H)
+                self.write_to_memory_at(address, self.l)?;
+                self.write_to_memory_at(address.wrapping_add(1), self.h)?;
+            }
+            Instruction::CMA => {
+                self.a = !self.a;
+            }
+            Instruction::DAA => self.decimal_adjust(),
+            Instruction::LHLD { address } => {
+                // (L) <- ((address)); (H) <- ((address + 1))
+                self.l = self.load_from_memory_at(address)?;
+                self.h = self.load_from_memory_at(address.wrapping_add(1))?;
+            }
             Instruction::STA { address } => {
                 self.write_to_memory_at(address, self.a)?;
             }
-            Instruction::STC => todo!(),
+            Instruction::STC => {
+                self.condition_codes.cy = 1;
+            }
             Instruction::LDA { address } => {
                 self.a = self.load_from_memory_at(address)?;
             }
-            Instruction::CMC => todo!(),
+            Instruction::CMC => {
+                self.condition_codes.cy ^= 1;
+            }
             Instruction::MOV {
                 source,
                 destination,
             } => {
                 if source == Reg::M {
-                    let address = self.load_register_pair(Reg::H);
-                    self.assign_value(destination, self.load_from_memory_at(address)?)
+                    let address = self.load_register_pair(Reg::H)?;
+                    let value = self.load_from_memory_at(address)?;
+                    self.assign_value(destination, value)?;
                 } else if destination == Reg::M {
-                    let address = self.load_register_pair(Reg::H);
-                    self.write_to_memory_at(address, self.get_register_val(source))?;
+                    let address = self.load_register_pair(Reg::H)?;
+                    self.write_to_memory_at(address, self.get_register_val(source)?)?;
                 } else {
-                    self.assign_value(destination, self.get_register_val(source));
+                    self.assign_value(destination, self.get_register_val(source)?)?;
                 }
             }
             Instruction::HLT => {
                 self.halted = true;
             }
             Instruction::ADD { register } => {
-                let (res, overflow) = self
-                    .load_from_memory_or_register(register)?
-                    .overflowing_add(self.a);
-
-                self.update_condition_codes(res, overflow);
-
-                self.a = res;
+                let value = self.load_from_memory_or_register(register)?;
+                self.a = self.add(value, 0);
             }
             Instruction::ADC { register } => {
-                let (res, overflow) = self
-                    .load_from_memory_or_register(register)?
-                    .overflowing_add(self.a);
-                let (res, overflow_two) = res.overflowing_add(self.condition_codes.cy);
-
-                self.update_condition_codes(res, overflow || overflow_two);
-
-                self.a = res;
+                let value = self.load_from_memory_or_register(register)?;
+                self.a = self.add(value, self.condition_codes.cy);
             }
             Instruction::SUB { register } => {
-                let (res, overflow) = self
-                    .a
-                    .overflowing_sub(self.load_from_memory_or_register(register)?);
-
-                self.update_condition_codes(res, overflow);
-
-                self.a = res;
+                let value = self.load_from_memory_or_register(register)?;
+                self.a = self.subtract(value, 0);
             }
             Instruction::SBB { register } => {
-                let (res, overflow) = self
-                    .a
-                    .overflowing_sub(self.load_from_memory_or_register(register)?);
-                let (res, overflow_two) = res.overflowing_sub(self.condition_codes.cy);
-
-                self.update_condition_codes(res, overflow || overflow_two);
-
-                self.a = res;
+                let value = self.load_from_memory_or_register(register)?;
+                self.a = self.subtract(value, self.condition_codes.cy);
+            }
+            Instruction::ANA { register } => {
+                let value = self.load_from_memory_or_register(register)?;
+                self.and(value);
+            }
+            Instruction::XRA { register } => {
+                let value = self.load_from_memory_or_register(register)?;
+                self.xor(value);
+            }
+            Instruction::ORA { register } => {
+                let value = self.load_from_memory_or_register(register)?;
+                self.or(value);
             }
-            Instruction::ANA { register } => todo!("{}", register),
-            Instruction::XRA { register } => todo!("{}", register),
-            Instruction::ORA { register } => todo!("{}", register),
-            Instruction::CMP { register } => todo!("{}", register),
-            Instruction::RNZ => todo!(),
+            Instruction::CMP { register } => {
+                let value = self.load_from_memory_or_register(register)?;
+                self.compare(value);
+            }
+            Instruction::RNZ => self.return_if(Condition::NotZero)?,
             Instruction::POP { register } => {
+                let value = self.pop()?;
                 if register == Reg::Psw {
-                    self.write_processor_status_word(self.load_from_memory_at(self.sp)?);
-                    self.a = self.load_from_memory_at(self.sp.wrapping_add(1))?;
+                    self.write_processor_status_word((value & 0xff) as u8);
+                    self.a = (value >> 8) as u8;
                 } else {
-                    let low = self.load_from_memory_at(self.sp)?;
-                    let high = self.load_from_memory_at(self.sp + 1)?;
-                    self.set_register_pair(register, ((high as u16) << 8) | low as u16);
-                }
-                self.sp = self.sp.wrapping_add(2);
-            }
-            Instruction::JNZ { address } => {
-                if 0 == self.condition_codes.z {
-                    self.pc = address - instruction.op_bytes() as u16;
+                    self.set_register_pair(register, value)?;
                 }
             }
+            Instruction::JNZ { address } => self.jump_if(Condition::NotZero, address),
             Instruction::JMP { address } => {
-                self.pc = address - instruction.op_bytes() as u16;
+                self.pc = address;
             }
-            Instruction::CNZ { address } => todo!("{}", address),
+            Instruction::CNZ { address } => self.call_if(Condition::NotZero, address)?,
             Instruction::PUSH { register } => {
-                if register == Reg::Psw {
-                    self.write_to_memory_at(self.sp.wrapping_sub(1), self.a)?;
-                    self.write_to_memory_at(self.sp.wrapping_sub(2), self.processor_status_word())?;
+                let value = if register == Reg::Psw {
+                    ((self.a as u16) << 8) | self.processor_status_word() as u16
                 } else {
-                    let value = self.load_register_pair(register);
-                    self.write_to_memory_at(self.sp.wrapping_sub(1), ((value >> 8) & 0xff) as u8)?;
-                    self.write_to_memory_at(self.sp.wrapping_sub(2), (value & 0xff) as u8)?;
-                }
-                self.sp = self.sp.wrapping_sub(2);
+                    self.load_register_pair(register)?
+                };
+                self.push(value)?;
             }
             Instruction::ADI { data } => {
-                let (res, overflow) = self.a.overflowing_add(data);
-
-                self.update_condition_codes(res, overflow);
-
-                self.a = res;
-            }
-            Instruction::RST { data } => {
-                self.write_to_memory_at(self.sp.wrapping_sub(1), ((self.pc >> 8) & 0xff) as u8)?;
-                self.write_to_memory_at(self.sp.wrapping_sub(2), (self.pc & 0x00FF) as u8)?;
-                self.sp = self.sp.wrapping_sub(2);
-                self.pc = ((8 * data) as u16).wrapping_sub(instruction.op_bytes() as u16);
+                self.a = self.add(data, 0);
             }
-            Instruction::RZ => todo!(),
+            Instruction::RST { data } => self.call(u16::from(data & 0b111) * 8)?,
+            Instruction::RZ => self.return_if(Condition::Zero)?,
             Instruction::RET => {
-                self.pc = self.memory[self.sp as usize] as u16
-                    | ((self.memory[(self.sp.wrapping_add(1)) as usize] as u16) << 8);
-                self.sp = self.sp.wrapping_add(2);
-            }
-            Instruction::JZ { address } => {
-                if 0 != self.condition_codes.z {
-                    self.pc = address - instruction.op_bytes() as u16;
-                }
-            }
-            Instruction::CZ { address } => todo!("{}", address),
-            Instruction::CALL { address } => {
-                let ret = self.pc + 2;
-                self.write_to_memory_at(self.sp.wrapping_sub(1), ((ret >> 8) & 0xff) as u8)?;
-                self.write_to_memory_at(self.sp.wrapping_sub(2), (ret & 0xff) as u8)?;
-                self.sp = self.sp.wrapping_sub(2);
-                self.pc = address.wrapping_sub(instruction.op_bytes().into()) as u16;
+                self.pc = self.pop()?;
             }
+            Instruction::JZ { address } => self.jump_if(Condition::Zero, address),
+            Instruction::CZ { address } => self.call_if(Condition::Zero, address)?,
+            Instruction::CALL { address } => self.call(address)?,
             Instruction::ACI { data } => {
-                let (res, overflow) = self.a.overflowing_add(data);
-                let (res, overflow_two) = res.overflowing_add(self.condition_codes.cy);
-
-                self.update_condition_codes(res, overflow || overflow_two);
-
-                self.a = res;
+                self.a = self.add(data, self.condition_codes.cy);
             }
-            Instruction::RNC => todo!(),
-            Instruction::JNC { address } => {
-                if 0 == self.condition_codes.cy {
-                    self.pc = address - instruction.op_bytes() as u16;
-                }
-            }
-            Instruction::OUT { data } => (self.on_bus_write)(data),
-            Instruction::CNC { address } => todo!("{}", address),
+            Instruction::RNC => self.return_if(Condition::NoCarry)?,
+            Instruction::JNC { address } => self.jump_if(Condition::NoCarry, address),
+            Instruction::OUT { data } => self.io.output(data, self.a),
+            Instruction::CNC { address } => self.call_if(Condition::NoCarry, address)?,
             Instruction::SUI { data } => {
-                let (res, overflow) = self.a.overflowing_sub(data);
-
-                self.update_condition_codes(res, overflow);
-
-                self.a = res;
+                self.a = self.subtract(data, 0);
             }
-            Instruction::RC => todo!(),
-            Instruction::JC { address } => {
-                if 0 != self.condition_codes.cy {
-                    self.pc = address - instruction.op_bytes() as u16;
-                }
+            Instruction::RC => self.return_if(Condition::Carry)?,
+            Instruction::JC { address } => self.jump_if(Condition::Carry, address),
+            Instruction::IN { data } => {
+                self.a = self.io.input(data, self.bus);
             }
-            Instruction::IN { data } => todo!("{}", data),
-            Instruction::CC { address } => todo!("{}", address),
+            Instruction::CC { address } => self.call_if(Condition::Carry, address)?,
             Instruction::SBI { data } => {
-                let (res, overflow) = self.a.overflowing_sub(data);
-                let (res, overflow_two) = res.overflowing_sub(self.condition_codes.cy);
-
-                self.update_condition_codes(res, overflow || overflow_two);
-
-                self.a = res;
-            }
-            Instruction::RPO => todo!(),
-            Instruction::JPO { address } => todo!("{}", address),
-            Instruction::XTHL => todo!(),
-            Instruction::CPO { address } => todo!("{}", address),
-            Instruction::ANI { data } => {
-                self.a &= data;
-                self.condition_codes.z = if self.a == 0 { 1 } else { 0 };
-                self.update_parity(self.a);
-                self.condition_codes.cy = 0;
-                self.condition_codes.ac = 0;
-            }
-            Instruction::RPE => {
-                if self.condition_codes.p == 1 {
-                    self.execute_instruction(Instruction::RET)?;
-                }
+                self.a = self.subtract(data, self.condition_codes.cy);
+            }
+            Instruction::RPO => self.return_if(Condition::ParityOdd)?,
+            Instruction::JPO { address } => self.jump_if(Condition::ParityOdd, address),
+            Instruction::XTHL => {
+                // (L) <-> ((SP)); (H) <-> ((SP + 1))
+                let l = self.load_from_memory_at(self.sp)?;
+                let h = self.load_from_memory_at(self.sp.wrapping_add(1))?;
+                self.write_to_memory_at(self.sp, self.l)?;
+                self.write_to_memory_at(self.sp.wrapping_add(1), self.h)?;
+                self.l = l;
+                self.h = h;
             }
-            Instruction::PCHL => todo!(),
-            Instruction::JPE { address } => todo!("{}", address),
+            Instruction::CPO { address } => self.call_if(Condition::ParityOdd, address)?,
+            Instruction::ANI { data } => self.and(data),
+            Instruction::RPE => self.return_if(Condition::ParityEven)?,
+            Instruction::PCHL => {
+                self.pc = self.load_register_pair(Reg::H)?;
+            }
+            Instruction::JPE { address } => self.jump_if(Condition::ParityEven, address),
             Instruction::XCHG => {
                 let h = self.load_from_memory_or_register(Reg::H)?;
                 let l = self.load_from_memory_or_register(Reg::L)?;
@@ -488,27 +971,36 @@ impl<T: FnMut(u8)> Cpu<T> {
                 self.l = e;
                 self.e = l;
             }
-            Instruction::CPE { address } => todo!("{}", address),
-            Instruction::XRI { data } => todo!("{}", data),
-            Instruction::RP => todo!(),
-            Instruction::JP { address } => todo!("{}", address),
-            Instruction::DI => todo!(),
-            Instruction::CP { address } => todo!("{}", address),
-            Instruction::ORI { data } => todo!("{}", data),
-            Instruction::RM => todo!(),
-            Instruction::SPHL => todo!(),
-            Instruction::JM { address } => todo!("{}", address),
-            Instruction::EI => todo!(),
-            Instruction::CM { address } => todo!("{}", address),
-            Instruction::CPI { data } => {
-                let result = self.a.wrapping_sub(data);
-                self.condition_codes.z = (result == 0) as u8;
-                self.condition_codes.s = (0x80 == (result & 0x80)) as u8;
-                self.update_parity(result);
-                self.condition_codes.cy = (self.a < data) as u8;
+            Instruction::CPE { address } => self.call_if(Condition::ParityEven, address)?,
+            Instruction::XRI { data } => self.xor(data),
+            Instruction::RP => self.return_if(Condition::Plus)?,
+            Instruction::JP { address } => self.jump_if(Condition::Plus, address),
+            Instruction::DI => {
+                self.int_enable = false;
+            }
+            Instruction::CP { address } => self.call_if(Condition::Plus, address)?,
+            Instruction::ORI { data } => self.or(data),
+            Instruction::RM => self.return_if(Condition::Minus)?,
+            Instruction::SPHL => {
+                self.sp = self.load_register_pair(Reg::H)?;
+            }
+            Instruction::JM { address } => self.jump_if(Condition::Minus, address),
+            Instruction::EI => {
+                self.int_enable = true;
+                self.interrupt_delay = true;
             }
+            Instruction::CM { address } => self.call_if(Condition::Minus, address)?,
+            Instruction::CPI { data } => self.compare(data),
+            Instruction::UndocumentedNoOp { opcode: _ } => (),
+            Instruction::UndocumentedJMP { address } => {
+                self.pc = address;
+            }
+            Instruction::UndocumentedRET => {
+                self.pc = self.pop()?;
+            }
+            Instruction::UndocumentedCALL { opcode: _, address } => self.call(address)?,
         }
-        Ok(())
+        Ok(cycles)
     }
 
     fn update_condition_codes(&mut self, value: u8, overflow: bool) {
@@ -519,6 +1011,141 @@ impl<T: FnMut(u8)> Cpu<T> {
         self.condition_codes.cy = if overflow { 1 } else { 0 };
     }
 
+    /// Push a 16 bit value onto the stack, high byte first
+    fn push(&mut self, value: u16) -> Result<(), Error> {
+        // An sp of 0 is the top of memory, where programs often start the
+        // stack, but a push from 1 wraps around and is below any limit
+        let top = match self.sp {
+            0 => 0x10000,
+            sp => u32::from(sp),
+        };
+        if self
+            .stack_limit
+            .is_some_and(|limit| top < u32::from(limit) + 2)
+        {
+            return Err(Error::StackOverflow {
+                sp: self.sp.wrapping_sub(2),
+            });
+        }
+        self.write_to_memory_at(self.sp.wrapping_sub(1), (value >> 8) as u8)?;
+        self.write_to_memory_at(self.sp.wrapping_sub(2), (value & 0xff) as u8)?;
+        self.sp = self.sp.wrapping_sub(2);
+        Ok(())
+    }
+
+    /// Pop a 16 bit value off the stack
+    fn pop(&mut self) -> Result<u16, Error> {
+        let low = self.load_from_memory_at(self.sp)?;
+        let high = self.load_from_memory_at(self.sp.wrapping_add(1))?;
+        self.sp = self.sp.wrapping_add(2);
+        Ok(((high as u16) << 8) | low as u16)
+    }
+
+    fn jump_if(&mut self, condition: Condition, address: u16) {
+        if self.condition_codes.check(condition) {
+            self.pc = address;
+        }
+    }
+
+    /// Push the return address (the pc of the following instruction) and
+    /// jump to `address`
+    fn call(&mut self, address: u16) -> Result<(), Error> {
+        self.push(self.pc)?;
+        self.pc = address;
+        Ok(())
+    }
+
+    fn call_if(&mut self, condition: Condition, address: u16) -> Result<(), Error> {
+        if self.condition_codes.check(condition) {
+            self.call(address)?;
+        }
+        Ok(())
+    }
+
+    fn return_if(&mut self, condition: Condition) -> Result<(), Error> {
+        if self.condition_codes.check(condition) {
+            self.pc = self.pop()?;
+        }
+        Ok(())
+    }
+
+    /// (A) <- (A) & value
+    ///
+    /// The 8080 sets AC to the logical OR of bit 3 of the two operands
+    fn and(&mut self, value: u8) {
+        let res = self.a & value;
+        self.update_condition_codes(res, false);
+        self.condition_codes.ac = (((self.a | value) & 0x08) != 0).into();
+        self.a = res;
+    }
+
+    /// (A) <- (A) ^ value
+    fn xor(&mut self, value: u8) {
+        self.a ^= value;
+        self.update_condition_codes(self.a, false);
+        self.condition_codes.ac = 0;
+    }
+
+    /// (A) <- (A) | value
+    fn or(&mut self, value: u8) {
+        self.a |= value;
+        self.update_condition_codes(self.a, false);
+        self.condition_codes.ac = 0;
+    }
+
+    /// Compute (A) + value + carry, setting every condition code. (A) itself
+    /// is left for the caller to update
+    fn add(&mut self, value: u8, carry: u8) -> u8 {
+        let res = self.a as u16 + value as u16 + carry as u16;
+
+        self.update_condition_codes(res as u8, res > 0xff);
+        self.condition_codes.ac = ((self.a & 0x0f) + (value & 0x0f) + carry > 0x0f).into();
+
+        res as u8
+    }
+
+    /// Compute (A) - value - borrow, setting every condition code. (A) itself
+    /// is left for the caller to update
+    ///
+    /// The 8080 subtracts by adding the one's complement of `value` with the
+    /// inverted borrow as carry in. AC is the carry out of bit 3 of that
+    /// addition, while CY is inverted so that it signals a borrow
+    fn subtract(&mut self, value: u8, borrow: u8) -> u8 {
+        let res = self.add(!value, (borrow == 0).into());
+        self.condition_codes.cy ^= 1;
+        res
+    }
+
+    /// Adjust (A) to form two 4 bit BCD digits after a BCD addition
+    ///
+    /// 6 is added to the low nibble if it is above 9 or AC is set, and 6 is
+    /// added to the high nibble if it is above 9 (after the low adjustment) or
+    /// CY is set. Both corrections are applied as a single addition, so AC is
+    /// the carry out of the low nibble. CY is only ever set, never reset
+    fn decimal_adjust(&mut self) {
+        let low = self.a & 0x0f;
+        let high = self.a >> 4;
+        let mut correction = 0;
+        let mut carry = self.condition_codes.cy;
+
+        if low > 9 || self.condition_codes.ac != 0 {
+            correction |= 0x06;
+        }
+        if high > 9 || (high == 9 && low > 9) || carry != 0 {
+            correction |= 0x60;
+            carry = 1;
+        }
+
+        self.a = self.add(correction, 0);
+        self.condition_codes.cy = carry;
+    }
+
+    /// Set the condition codes as if `value` was subtracted from (A), leaving
+    /// (A) unchanged
+    fn compare(&mut self, value: u8) {
+        self.subtract(value, 0);
+    }
+
     pub(crate) fn processor_status_word(&self) -> u8 {
         self.condition_codes.s << 7
             | (self.condition_codes.z << 6)
@@ -528,7 +1155,7 @@ impl<T: FnMut(u8)> Cpu<T> {
             | self.condition_codes.cy
     }
 
-    fn write_processor_status_word(&mut self, processor_status_word: u8) {
+    pub(crate) fn write_processor_status_word(&mut self, processor_status_word: u8) {
         self.condition_codes.cy = processor_status_word & 0b1;
         self.condition_codes.p = (processor_status_word & 0b100) >> 2;
         self.condition_codes.ac = (processor_status_word & 0b10000) >> 4;
@@ -536,48 +1163,85 @@ impl<T: FnMut(u8)> Cpu<T> {
         self.condition_codes.s = (processor_status_word & 0b10000000) >> 7;
     }
 
-    fn load_from_memory(&self) -> Result<u8, Error> {
-        self.memory
-            .iter()
-            .nth((((self.h as u16) << 8) | self.l as u16).into())
-            .copied()
-            .ok_or_else(|| Error::BadMemoryAccess((((self.h as u16) << 8) as u8 | self.l).into()))
+    /// Load the value at the address in H and L
+    fn load_from_memory(&mut self) -> Result<u8, Error> {
+        let address = self.load_register_pair(Reg::H)?;
+        self.load_from_memory_at(address)
     }
 
-    fn load_from_memory_at(&self, address: u16) -> Result<u8, Error> {
-        self.memory
-            .iter()
-            .nth(address as usize)
-            .copied()
-            .ok_or(Error::BadMemoryAccess(address))
+    fn load_from_memory_at(&mut self, address: u16) -> Result<u8, Error> {
+        let value = self.memory.read(address);
+        self.memory_accesses.push(MemoryAccess {
+            address,
+            kind: AccessKind::Read,
+            value,
+        });
+        Ok(value)
     }
 
-    fn write_to_memory(&mut self, val: u8) {
-        let dest = self
-            .memory
-            .iter_mut()
-            .nth((((self.h as u16) << 8) | self.l as u16).into())
-            .unwrap();
-
-        *dest = val;
+    /// Write the given value to the address in H and L
+    fn write_to_memory(&mut self, val: u8) -> Result<(), Error> {
+        self.write_to_memory_at(self.load_register_pair(Reg::H)?, val)
     }
 
     /// Write the given value to the given address
     fn write_to_memory_at(&mut self, address: u16, val: u8) -> Result<(), Error> {
-        let dest = self
-            .memory
-            .iter_mut()
-            .nth(address as usize)
-            .ok_or(Error::BadMemoryAccess(address))?;
-        *dest = val;
+        self.memory_accesses.push(MemoryAccess {
+            address,
+            kind: AccessKind::Write,
+            value: val,
+        });
+        if !self.journal.recording() {
+            return self.memory.write(address, val);
+        }
+
+        let previous = self.memory.peek(address);
+        self.memory.write(address, val)?;
+        self.journal.record_write(address, previous);
         Ok(())
     }
 
-    fn load_from_memory_or_register(&self, memory_or_register: Reg) -> Result<u8, Error> {
+    fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            pc: self.pc,
+            sp: self.sp,
+            condition_codes: self.condition_codes,
+            int_enable: self.int_enable,
+            interrupt_delay: self.interrupt_delay,
+            halted: self.halted,
+            cycles: self.cycles,
+        }
+    }
+
+    fn restore_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.condition_codes = registers.condition_codes;
+        self.int_enable = registers.int_enable;
+        self.interrupt_delay = registers.interrupt_delay;
+        self.halted = registers.halted;
+        self.cycles = registers.cycles;
+    }
+
+    fn load_from_memory_or_register(&mut self, memory_or_register: Reg) -> Result<u8, Error> {
         if memory_or_register == Reg::M {
             self.load_from_memory()
         } else {
-            Ok(self.get_register_val(memory_or_register))
+            self.get_register_val(memory_or_register)
         }
     }
 
@@ -585,44 +1249,52 @@ impl<T: FnMut(u8)> Cpu<T> {
         self.condition_codes.p = if val.parity() { 1 } else { 0 };
     }
 
-    // TODO - Make this return a `Result` so we don't have to panic
-    fn load_register_pair(&self, reg: Reg) -> u16 {
+    fn load_register_pair(&self, reg: Reg) -> Result<u16, Error> {
         match reg {
-            Reg::A => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::A"),
-            Reg::B => ((self.b as u16) << 8) + self.c as u16,
-            Reg::C => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::C"),
-            Reg::D => ((self.d as u16) << 8) + self.e as u16,
-            Reg::E => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::E"),
-            Reg::SP => self.sp,
-            Reg::H => ((self.h as u16) << 8) + self.l as u16,
-            Reg::L => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::L"),
-            Reg::Psw => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::Psw"),
-            Reg::M => panic!("Invalid Arg: Called `Cpu.load_register_pair` with Reg::M"),
+            Reg::B => Ok(((self.b as u16) << 8) + self.c as u16),
+            Reg::D => Ok(((self.d as u16) << 8) + self.e as u16),
+            Reg::SP => Ok(self.sp),
+            Reg::H => Ok(((self.h as u16) << 8) + self.l as u16),
+            Reg::A | Reg::C | Reg::E | Reg::L | Reg::Psw | Reg::M => {
+                Err(Error::InvalidRegister(reg))
+            }
         }
     }
 
-    fn set_register_pair(&mut self, reg: Reg, val: u16) {
+    fn set_register_pair(&mut self, reg: Reg, val: u16) -> Result<(), Error> {
         match reg {
-            Reg::A => todo!(),
             Reg::B => {
                 self.b = (val >> 8) as u8;
                 self.c = (val & 0xff) as u8;
             }
-            Reg::C => todo!(),
             Reg::D => {
                 self.d = (val >> 8) as u8;
                 self.e = (val & 0xff) as u8;
             }
-            Reg::E => todo!(),
             Reg::SP => self.sp = val,
             Reg::H => {
                 self.h = (val >> 8) as u8;
                 self.l = (val & 0xff) as u8;
             }
-            Reg::L => todo!(),
-            Reg::Psw => todo!(),
-            Reg::M => todo!(),
+            Reg::A | Reg::C | Reg::E | Reg::L | Reg::Psw | Reg::M => {
+                return Err(Error::InvalidRegister(reg))
+            }
         }
+        Ok(())
+    }
+}
+
+/// Decode the instruction at `pc`, reading its bytes with `read`. Operands
+/// past 0xffff wrap around to 0x0000, as they do on the 8080
+fn decode_from(pc: u16, mut read: impl FnMut(u16) -> u8) -> Instruction {
+    let opcode = read(pc);
+    let bytes: Vec<u8> = std::iter::once(opcode)
+        .chain((1..Instruction::length(opcode)).map(|offset| read(pc.wrapping_add(offset.into()))))
+        .collect();
+
+    match Instruction::decode(&bytes) {
+        Ok(instruction) => instruction,
+        Err(err) => unreachable!("{:?} decoding a whole instruction", err),
     }
 }
 
@@ -0,0 +1,377 @@
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+
+use crate::{AccessKind, Cpu, Error, Instruction, Io, MemoryBus, SymbolTable};
+
+/// The memory accesses a watchpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: AccessKind) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortDirection {
+    /// IN
+    In,
+    /// OUT
+    Out,
+    /// Either
+    Any,
+}
+
+/// Families of instructions that can be broken on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionClass {
+    /// RST n
+    Rst,
+    /// CALL and the conditional calls
+    Call,
+    /// RET and the conditional returns
+    Return,
+    /// JMP, the conditional jumps and PCHL
+    Jump,
+    /// IN and OUT
+    Io,
+    /// EI and DI
+    InterruptControl,
+    /// HLT
+    Halt,
+    /// Any of the undocumented opcodes
+    Undocumented,
+}
+
+impl InstructionClass {
+    pub fn matches(self, instruction: &Instruction) -> bool {
+        use Instruction::*;
+
+        match self {
+            InstructionClass::Rst => matches!(instruction, RST { .. }),
+            InstructionClass::Call => matches!(
+                instruction,
+                CALL { .. }
+                    | CNZ { .. }
+                    | CZ { .. }
+                    | CNC { .. }
+                    | CC { .. }
+                    | CPO { .. }
+                    | CPE { .. }
+                    | CP { .. }
+                    | CM { .. }
+                    | UndocumentedCALL { .. }
+            ),
+            InstructionClass::Return => matches!(
+                instruction,
+                RET | RNZ | RZ | RNC | RC | RPO | RPE | RP | RM | UndocumentedRET
+            ),
+            InstructionClass::Jump => matches!(
+                instruction,
+                JMP { .. }
+                    | JNZ { .. }
+                    | JZ { .. }
+                    | JNC { .. }
+                    | JC { .. }
+                    | JPO { .. }
+                    | JPE { .. }
+                    | JP { .. }
+                    | JM { .. }
+                    | PCHL
+                    | UndocumentedJMP { .. }
+            ),
+            InstructionClass::Io => matches!(instruction, IN { .. } | OUT { .. }),
+            InstructionClass::InterruptControl => matches!(instruction, EI | DI),
+            InstructionClass::Halt => matches!(instruction, HLT),
+            InstructionClass::Undocumented => instruction.is_undocumented(),
+        }
+    }
+}
+
+/// Why `Debugger::run` stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// The pc reached a breakpoint. The instruction there hasn't executed
+    Breakpoint(u16),
+    /// The last instruction made an access a watchpoint covers. `watch` is
+    /// the kind of the watchpoint, and `kind` the access that triggered it
+    Watchpoint {
+        address: u16,
+        kind: AccessKind,
+        value: u8,
+        watch: WatchKind,
+    },
+    /// The last instruction was an IN or OUT on a port being watched.
+    /// `value` is the byte read or written
+    Port {
+        port: u8,
+        direction: PortDirection,
+        value: u8,
+    },
+    /// The next instruction is in a class being broken on. It hasn't
+    /// executed
+    Instruction {
+        pc: u16,
+        instruction: Instruction,
+        class: InstructionClass,
+    },
+    /// The CPU is halted and nothing can wake it
+    Halted,
+    /// The cycle budget given to `Debugger::run_for` ran out
+    BudgetExhausted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Watchpoint {
+    range: RangeInclusive<u16>,
+    kind: WatchKind,
+}
+
+/// Breakpoints and watchpoints, and run loops that stop on them
+///
+/// The debugger doesn't own a CPU, so the same set of breakpoints can be
+/// used with whichever one a frontend is showing. Running again after a stop
+/// before an instruction (a breakpoint or instruction class) executes that
+/// instruction rather than stopping on it again straight away.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    port_breakpoints: Vec<(u8, PortDirection)>,
+    instruction_breakpoints: Vec<InstructionClass>,
+    symbols: SymbolTable,
+    // The pc of the last stop before an instruction, so that resuming steps
+    // over it
+    stopped_before: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether there was a breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    /// Returns whether a matching watchpoint was found
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.range != range || watchpoint.kind != kind);
+        self.watchpoints.len() != before
+    }
+
+    pub fn add_port_breakpoint(&mut self, port: u8, direction: PortDirection) {
+        self.port_breakpoints.push((port, direction));
+    }
+
+    /// Returns whether a matching port breakpoint was found
+    pub fn remove_port_breakpoint(&mut self, port: u8, direction: PortDirection) -> bool {
+        let before = self.port_breakpoints.len();
+        self.port_breakpoints
+            .retain(|&breakpoint| breakpoint != (port, direction));
+        self.port_breakpoints.len() != before
+    }
+
+    pub fn add_instruction_breakpoint(&mut self, class: InstructionClass) {
+        self.instruction_breakpoints.push(class);
+    }
+
+    /// Returns whether the class was being broken on
+    pub fn remove_instruction_breakpoint(&mut self, class: InstructionClass) -> bool {
+        let before = self.instruction_breakpoints.len();
+        self.instruction_breakpoints.retain(|&other| other != class);
+        self.instruction_breakpoints.len() != before
+    }
+
+    /// Names for addresses, used by `describe` and `add_breakpoint_at`
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Break at the address named `name`. Returns whether there's a symbol
+    /// with that name
+    pub fn add_breakpoint_at(&mut self, name: &str) -> bool {
+        match self.symbols.address(name) {
+            Some(pc) => {
+                self.add_breakpoint(pc);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A line for the user about why the CPU stopped, with addresses named
+    /// by the symbol table
+    pub fn describe(&self, reason: &StopReason) -> String {
+        let symbols = &self.symbols;
+        match reason {
+            StopReason::Breakpoint(pc) => format!("Breakpoint at {}", symbols.describe(*pc)),
+            StopReason::Watchpoint {
+                address,
+                kind,
+                value,
+                ..
+            } => format!(
+                "Watchpoint: {:?} of {:#04x} at {}",
+                kind,
+                value,
+                symbols.describe(*address)
+            ),
+            StopReason::Port {
+                port,
+                direction,
+                value,
+            } => format!("Port {:?} of {:#04x} on {:#04x}", direction, value, port),
+            StopReason::Instruction {
+                pc,
+                instruction,
+                class,
+            } => format!(
+                "{:?} instruction at {}: {}",
+                class,
+                symbols.describe(*pc),
+                symbols.format_instruction(instruction)
+            ),
+            StopReason::Halted => "Halted".to_owned(),
+            StopReason::BudgetExhausted => "Cycle budget exhausted".to_owned(),
+        }
+    }
+
+    /// Run until something stops the CPU
+    pub fn run<IO: Io, M: MemoryBus>(&mut self, cpu: &mut Cpu<IO, M>) -> Result<StopReason, Error> {
+        loop {
+            if let Some(reason) = self.step_checked(cpu, false)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Like `run`, but give up once at least `budget` clock cycles have
+    /// elapsed
+    pub fn run_for<IO: Io, M: MemoryBus>(
+        &mut self,
+        cpu: &mut Cpu<IO, M>,
+        budget: u64,
+    ) -> Result<StopReason, Error> {
+        let end = cpu.cycles().saturating_add(budget);
+        while cpu.cycles() < end {
+            if let Some(reason) = self.step_checked(cpu, false)? {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::BudgetExhausted)
+    }
+
+    /// Execute a single instruction, ignoring breakpoints on it, and report
+    /// any watchpoint or port breakpoint it triggers
+    pub fn step<IO: Io, M: MemoryBus>(
+        &mut self,
+        cpu: &mut Cpu<IO, M>,
+    ) -> Result<Option<StopReason>, Error> {
+        self.step_checked(cpu, true)
+    }
+
+    /// Step `cpu`, checking the breakpoints that stop before an instruction
+    /// unless stepping over them, and those that stop after it
+    fn step_checked<IO: Io, M: MemoryBus>(
+        &mut self,
+        cpu: &mut Cpu<IO, M>,
+        step_over: bool,
+    ) -> Result<Option<StopReason>, Error> {
+        let Some(instruction) = cpu.next_instruction()? else {
+            return Ok(Some(StopReason::Halted));
+        };
+
+        let resuming = self.stopped_before.take() == Some(cpu.pc());
+        if !step_over && !resuming {
+            let class = self
+                .instruction_breakpoints
+                .iter()
+                .find(|class| class.matches(&instruction));
+            let reason = if self.breakpoints.contains(&cpu.pc()) {
+                Some(StopReason::Breakpoint(cpu.pc()))
+            } else {
+                class.map(|&class| StopReason::Instruction {
+                    pc: cpu.pc(),
+                    instruction,
+                    class,
+                })
+            };
+            if reason.is_some() {
+                self.stopped_before = Some(cpu.pc());
+                return Ok(reason);
+            }
+        }
+
+        cpu.step()?;
+
+        for access in cpu.memory_accesses() {
+            let watched = self.watchpoints.iter().find(|watchpoint| {
+                watchpoint.range.contains(&access.address) && watchpoint.kind.matches(access.kind)
+            });
+            if let Some(watchpoint) = watched {
+                return Ok(Some(StopReason::Watchpoint {
+                    address: access.address,
+                    kind: access.kind,
+                    value: access.value,
+                    watch: watchpoint.kind,
+                }));
+            }
+        }
+
+        let port = match instruction {
+            Instruction::IN { data } => Some((data, PortDirection::In)),
+            Instruction::OUT { data } => Some((data, PortDirection::Out)),
+            _ => None,
+        };
+        if let Some((port, direction)) = port {
+            let watched = self
+                .port_breakpoints
+                .iter()
+                .any(|&(watched, watched_direction)| {
+                    watched == port
+                        && (watched_direction == PortDirection::Any
+                            || watched_direction == direction)
+                });
+            if watched {
+                return Ok(Some(StopReason::Port {
+                    port,
+                    direction,
+                    value: cpu.a(),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{Instruction, SymbolTable};
+
+/// One instruction, or one byte of data, at an address
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` for a data byte, rendered as `DB`
+    pub instruction: Option<Instruction>,
+}
+
+impl DisassembledLine {
+    /// Show the line with addresses named by `symbols`, after a label line
+    /// if its own address has a name
+    pub fn display_with<'a>(&'a self, symbols: &'a SymbolTable) -> impl fmt::Display + 'a {
+        WithSymbols {
+            line: self,
+            symbols,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, symbols: Option<&SymbolTable>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{:#06x} {:<8} ", self.address, bytes)?;
+
+        match (&self.instruction, symbols) {
+            (Some(instruction), Some(symbols)) => {
+                write!(f, "{}", symbols.format_instruction(instruction))
+            }
+            (Some(instruction), None) => write!(f, "{}", instruction),
+            (None, _) => write!(f, "DB {:#04x}", self.bytes[0]),
+        }
+    }
+}
+
+impl fmt::Display for DisassembledLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+struct WithSymbols<'a> {
+    line: &'a DisassembledLine,
+    symbols: &'a SymbolTable,
+}
+
+impl fmt::Display for WithSymbols<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = self.symbols.name(self.line.address) {
+            writeln!(f, "{}:", name)?;
+        }
+        self.line.write(f, Some(self.symbols))
+    }
+}
+
+/// A linear sweep over some bytes, see [`disassemble`]
+#[derive(Debug, Clone)]
+pub struct Disassembly<'a> {
+    bin: &'a [u8],
+    base: u16,
+    position: usize,
+}
+
+impl Iterator for Disassembly<'_> {
+    type Item = DisassembledLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self
+            .bin
+            .get(self.position..)
+            .filter(|rest| !rest.is_empty())?;
+        let address = self.base.wrapping_add(self.position as u16);
+
+        // Only an instruction cut off by the end of the input fails to
+        // decode, and its bytes can only be data
+        let line = match Instruction::decode(rest) {
+            Ok(instruction) => DisassembledLine {
+                address,
+                bytes: rest[..instruction.op_bytes().into()].to_vec(),
+                instruction: Some(instruction),
+            },
+            Err(_) => DisassembledLine {
+                address,
+                bytes: vec![rest[0]],
+                instruction: None,
+            },
+        };
+        self.position += line.bytes.len();
+        Some(line)
+    }
+}
+
+/// Disassemble `bin`, loaded at `base`, from start to end
+pub fn disassemble(bin: &[u8], base: u16) -> Disassembly<'_> {
+    Disassembly {
+        bin,
+        base,
+        position: 0,
+    }
+}
+
+/// The code found by following a program's control flow, see [`analyze`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis<'a> {
+    bin: &'a [u8],
+    base: u16,
+    instructions: BTreeMap<u16, Instruction>,
+    code: Vec<bool>,
+    unresolved_jumps: BTreeSet<u16>,
+}
+
+impl Analysis<'_> {
+    fn offset(&self, address: u16) -> Option<usize> {
+        Some(usize::from(address.wrapping_sub(self.base))).filter(|&offset| offset < self.bin.len())
+    }
+
+    /// Whether the byte at `address` is part of a reachable instruction
+    pub fn is_code(&self, address: u16) -> bool {
+        self.offset(address).is_some_and(|offset| self.code[offset])
+    }
+
+    /// The reachable instruction starting at `address`
+    pub fn instruction_at(&self, address: u16) -> Option<Instruction> {
+        self.instructions.get(&address).copied()
+    }
+
+    /// Every reachable instruction and its address, in address order
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, Instruction)> + '_ {
+        self.instructions
+            .iter()
+            .map(|(&address, &instruction)| (address, instruction))
+    }
+
+    /// The addresses of reachable PCHL instructions, whose targets couldn't
+    /// be followed
+    pub fn unresolved_jumps(&self) -> impl Iterator<Item = u16> + '_ {
+        self.unresolved_jumps.iter().copied()
+    }
+
+    /// The whole input as lines, with reachable instructions disassembled
+    /// and everything else as data bytes
+    ///
+    /// An instruction starting inside another one is only listed through
+    /// `instructions`.
+    pub fn lines(&self) -> impl Iterator<Item = DisassembledLine> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let byte = *self.bin.get(offset)?;
+            let address = self.base.wrapping_add(offset as u16);
+            let line = match self.instruction_at(address) {
+                Some(instruction) => DisassembledLine {
+                    address,
+                    bytes: self.bin[offset..][..instruction.op_bytes().into()].to_vec(),
+                    instruction: Some(instruction),
+                },
+                None => DisassembledLine {
+                    address,
+                    bytes: vec![byte],
+                    instruction: None,
+                },
+            };
+            offset += line.bytes.len();
+            Some(line)
+        })
+    }
+}
+
+/// Disassemble `bin`, loaded at `base`, by following jumps, calls and RSTs
+/// from 0x0000, the RST vectors and `entry_points`
+///
+/// Entry points and branch targets outside of `bin` are ignored, as is an
+/// instruction cut off by the end of it.
+pub fn analyze<'a>(bin: &'a [u8], base: u16, entry_points: &[u16]) -> Analysis<'a> {
+    let mut analysis = Analysis {
+        bin,
+        base,
+        instructions: BTreeMap::new(),
+        code: vec![false; bin.len()],
+        unresolved_jumps: BTreeSet::new(),
+    };
+
+    let mut pending: Vec<u16> = (0..8).map(|vector| vector * 8).collect();
+    pending.extend(entry_points);
+
+    while let Some(address) = pending.pop() {
+        if analysis.instructions.contains_key(&address) {
+            continue;
+        }
+        let Some(offset) = analysis.offset(address) else {
+            continue;
+        };
+        let Ok(instruction) = Instruction::decode(&bin[offset..]) else {
+            continue;
+        };
+
+        let length = usize::from(instruction.op_bytes());
+        for code in &mut analysis.code[offset..offset + length] {
+            *code = true;
+        }
+        analysis.instructions.insert(address, instruction);
+
+        if instruction == Instruction::PCHL {
+            analysis.unresolved_jumps.insert(address);
+        }
+        if instruction.falls_through() {
+            pending.push(address.wrapping_add(length as u16));
+        }
+        pending.extend(instruction.branch_target());
+    }
+
+    analysis
+}
@@ -0,0 +1,477 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::{Cpu, Debugger, Io, Memory, MemoryBus, StopReason, WatchKind};
+
+/// The target description sent to GDB
+pub(crate) const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.eighty-eighty.cpu">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psw" bitsize="16"/>
+  </feature>
+</target>
+"#;
+
+/// Register widths in bytes, in register number order
+const REGISTER_SIZES: [usize; 10] = [1, 1, 1, 1, 1, 1, 1, 2, 2, 2];
+
+/// How many cycles to run between checks for an interrupt from GDB
+const CONTINUE_SLICE: u64 = 100_000;
+
+/// A connection GDB can talk to the stub over
+pub trait GdbConnection: Read + Write {
+    /// Check, without blocking, whether GDB has sent an interrupt (Ctrl-C)
+    /// while the target runs
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+/// Read a byte if one is waiting, using `set_nonblocking` to avoid waiting
+fn poll_byte<S: Read>(
+    stream: &mut S,
+    set_nonblocking: impl Fn(&S, bool) -> io::Result<()>,
+) -> io::Result<Option<u8>> {
+    set_nonblocking(stream, true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    set_nonblocking(stream, false)?;
+
+    match result {
+        Ok(1) => Ok(Some(byte[0])),
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl GdbConnection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        Ok(poll_byte(self, TcpStream::set_nonblocking)? == Some(0x03))
+    }
+}
+
+#[cfg(unix)]
+impl GdbConnection for std::os::unix::net::UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        Ok(poll_byte(self, Self::set_nonblocking)? == Some(0x03))
+    }
+}
+
+/// What to do after handling a packet
+enum Action {
+    Reply(String),
+    /// Reply, then end the session
+    Detach(String),
+    /// End the session without replying
+    Kill,
+}
+
+/// A GDB remote serial protocol stub for a CPU
+///
+/// Registers are numbered A, B, C, D, E, H, L, SP, PC and PSW, as described
+/// by the target XML the stub sends. 16 bit registers are little endian, and
+/// PSW has A in its high byte and the flags in its low byte, as pushed by
+/// `PUSH PSW`. Software breakpoints (`Z0`) and watchpoints (`Z2` to `Z4`)
+/// are added to the server's [`Debugger`].
+pub struct GdbServer<IO: Io, M: MemoryBus = Memory> {
+    cpu: Cpu<IO, M>,
+    debugger: Debugger,
+}
+
+impl<IO: Io, M: MemoryBus> GdbServer<IO, M> {
+    pub fn new(cpu: Cpu<IO, M>) -> Self {
+        Self {
+            cpu,
+            debugger: Debugger::new(),
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu<IO, M> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<IO, M> {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> Cpu<IO, M> {
+        self.cpu
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// Wait for GDB to connect to `address`, e.g. `"localhost:1234"`, and
+    /// serve that one session
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        self.serve(stream)
+    }
+
+    /// Serve a session until GDB detaches, kills the target or disconnects
+    pub fn serve<C: GdbConnection>(&mut self, mut connection: C) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut connection)? {
+            match self.handle_packet(&packet, &mut connection)? {
+                Action::Reply(reply) => write_packet(&mut connection, &reply)?,
+                Action::Detach(reply) => {
+                    write_packet(&mut connection, &reply)?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_packet<C: GdbConnection>(
+        &mut self,
+        packet: &str,
+        connection: &mut C,
+    ) -> io::Result<Action> {
+        let split = packet
+            .char_indices()
+            .nth(1)
+            .map_or(packet.len(), |(index, _)| index);
+        let reply = match packet.split_at(split) {
+            ("?", _) => "S05".to_owned(),
+            ("g", _) => self.read_registers(),
+            ("G", values) => ok_or_error(self.write_registers(values)),
+            ("p", register) => match parse_hex(register) {
+                Some(register) => self.read_register(register as usize),
+                None => error(),
+            },
+            ("P", assignment) => ok_or_error(self.write_register(assignment)),
+            ("m", range) => self.read_memory(range).unwrap_or_else(error),
+            ("M", write) => ok_or_error(self.write_memory(write)),
+            ("s", address) => {
+                if let Some(address) = parse_hex(address) {
+                    self.cpu.pc = address as u16;
+                }
+                match self.debugger.step(&mut self.cpu) {
+                    Ok(reason) => stop_reply(reason.as_ref()),
+                    Err(_) => "S04".to_owned(),
+                }
+            }
+            ("c", address) => {
+                if let Some(address) = parse_hex(address) {
+                    self.cpu.pc = address as u16;
+                }
+                self.resume(connection)?
+            }
+            ("Z", breakpoint) => ok_or_error(self.set_breakpoint(breakpoint, true)),
+            ("z", breakpoint) => ok_or_error(self.set_breakpoint(breakpoint, false)),
+            ("H", _) => "OK".to_owned(),
+            ("D", _) => return Ok(Action::Detach("OK".to_owned())),
+            ("k", _) => return Ok(Action::Kill),
+            _ => self.query(packet),
+        };
+        Ok(Action::Reply(reply))
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;swbreak+".to_owned()
+        } else if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_xfer(TARGET_XML.as_bytes(), annex).unwrap_or_else(error)
+        } else if packet == "qAttached" {
+            "1".to_owned()
+        } else if packet == "qC" {
+            "QC1".to_owned()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_owned()
+        } else if packet == "qsThreadInfo" {
+            "l".to_owned()
+        } else {
+            // An empty reply tells GDB the packet isn't supported
+            String::new()
+        }
+    }
+
+    /// Run until the debugger stops the CPU or GDB interrupts it
+    fn resume<C: GdbConnection>(&mut self, connection: &mut C) -> io::Result<String> {
+        loop {
+            match self.debugger.run_for(&mut self.cpu, CONTINUE_SLICE) {
+                Ok(StopReason::BudgetExhausted) => {
+                    if connection.poll_interrupt()? {
+                        return Ok("S02".to_owned());
+                    }
+                }
+                Ok(reason) => return Ok(stop_reply(Some(&reason))),
+                Err(_) => return Ok("S04".to_owned()),
+            }
+        }
+    }
+
+    /// The registers as little endian bytes, in register number order
+    fn register_bytes(&self, register: usize) -> Option<Vec<u8>> {
+        let cpu = &self.cpu;
+        Some(match register {
+            0 => vec![cpu.a],
+            1 => vec![cpu.b],
+            2 => vec![cpu.c],
+            3 => vec![cpu.d],
+            4 => vec![cpu.e],
+            5 => vec![cpu.h],
+            6 => vec![cpu.l],
+            7 => cpu.sp.to_le_bytes().to_vec(),
+            8 => cpu.pc.to_le_bytes().to_vec(),
+            9 => vec![cpu.processor_status_word(), cpu.a],
+            _ => return None,
+        })
+    }
+
+    fn set_register_bytes(&mut self, register: usize, bytes: &[u8]) {
+        let cpu = &mut self.cpu;
+        match (register, bytes) {
+            (0, &[value]) => cpu.a = value,
+            (1, &[value]) => cpu.b = value,
+            (2, &[value]) => cpu.c = value,
+            (3, &[value]) => cpu.d = value,
+            (4, &[value]) => cpu.e = value,
+            (5, &[value]) => cpu.h = value,
+            (6, &[value]) => cpu.l = value,
+            (7, &[low, high]) => cpu.sp = u16::from_le_bytes([low, high]),
+            (8, &[low, high]) => cpu.pc = u16::from_le_bytes([low, high]),
+            (9, &[flags, a]) => {
+                cpu.write_processor_status_word(flags);
+                cpu.a = a;
+            }
+            _ => unreachable!("register sizes are checked"),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_SIZES.len())
+            .filter_map(|register| self.register_bytes(register))
+            .map(|bytes| encode_hex(&bytes))
+            .collect()
+    }
+
+    fn read_register(&self, register: usize) -> String {
+        self.register_bytes(register)
+            .map(|bytes| encode_hex(&bytes))
+            .unwrap_or_else(error)
+    }
+
+    fn write_registers(&mut self, values: &str) -> Option<()> {
+        let bytes = decode_hex(values)?;
+        if bytes.len() != REGISTER_SIZES.iter().sum::<usize>() {
+            return None;
+        }
+
+        let mut rest = &bytes[..];
+        for (register, &size) in REGISTER_SIZES.iter().enumerate() {
+            let (value, remaining) = rest.split_at(size);
+            self.set_register_bytes(register, value);
+            rest = remaining;
+        }
+        Some(())
+    }
+
+    fn write_register(&mut self, assignment: &str) -> Option<()> {
+        let (register, value) = assignment.split_once('=')?;
+        let register = parse_hex(register)? as usize;
+        let value = decode_hex(value)?;
+        if REGISTER_SIZES.get(register) != Some(&value.len()) {
+            return None;
+        }
+        self.set_register_bytes(register, &value);
+        Some(())
+    }
+
+    fn read_memory(&self, range: &str) -> Option<String> {
+        let (address, length) = range.split_once(',')?;
+        let address = parse_hex(address)?;
+        let length = parse_hex(length)?;
+        if address > 0xffff {
+            return None;
+        }
+
+        // Reads stop at the end of the address space
+        let bytes: Vec<u8> = (address..address.saturating_add(length).min(0x1_0000))
+            .map(|address| self.cpu.memory().peek(address as u16))
+            .collect();
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, write: &str) -> Option<()> {
+        let (range, data) = write.split_once(':')?;
+        let (address, length) = range.split_once(',')?;
+        let address = parse_hex(address)?;
+        let data = decode_hex(data)?;
+        if data.len() != parse_hex(length)? as usize || address as usize + data.len() > 0x1_0000 {
+            return None;
+        }
+
+        for (offset, &value) in data.iter().enumerate() {
+            self.cpu
+                .memory_mut()
+                .poke((address as usize + offset) as u16, value);
+        }
+        Some(())
+    }
+
+    /// Handle `Z` and `z`. `type,address,kind`, where type 0 is a software
+    /// breakpoint and 2 to 4 are write, read and access watchpoints
+    fn set_breakpoint(&mut self, breakpoint: &str, insert: bool) -> Option<()> {
+        let mut fields = breakpoint.split(',');
+        let kind = fields.next()?;
+        let address = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?)?.max(1);
+        let address = u16::try_from(address).ok()?;
+        let end = u16::try_from(u32::from(address).checked_add(length - 1)?).ok()?;
+
+        let watch = match kind {
+            "0" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+        if insert {
+            self.debugger.add_watchpoint(address..=end, watch);
+        } else {
+            self.debugger.remove_watchpoint(address..=end, watch);
+        }
+        Some(())
+    }
+}
+
+/// The reply to `s`, `c` and `?` for why the CPU stopped
+fn stop_reply(reason: Option<&StopReason>) -> String {
+    match reason {
+        Some(StopReason::Breakpoint(_)) => "T05swbreak:;".to_owned(),
+        Some(StopReason::Watchpoint { address, watch, .. }) => {
+            let watch = match watch {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T05{}:{:04x};", watch, address)
+        }
+        _ => "S05".to_owned(),
+    }
+}
+
+/// Reply to `qXfer` with the part of `data` described by `offset,length`
+fn read_xfer(data: &[u8], annex: &str) -> Option<String> {
+    let (offset, length) = annex.split_once(',')?;
+    let offset = (parse_hex(offset)? as usize).min(data.len());
+    let end = offset.saturating_add(parse_hex(length)? as usize);
+
+    let (marker, chunk) = if end < data.len() {
+        ('m', &data[offset..end])
+    } else {
+        ('l', &data[offset..])
+    };
+    let mut reply = marker.to_string();
+    for &byte in chunk {
+        // `#`, `$`, `}` and `*` are escaped in binary data
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            reply.push('}');
+            reply.push((byte ^ 0x20) as char);
+        } else {
+            reply.push(byte as char);
+        }
+    }
+    Some(reply)
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_owned(),
+        None => error(),
+    }
+}
+
+fn error() -> String {
+    "E01".to_owned()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, u8::wrapping_add)
+}
+
+fn read_byte(stream: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Read the next packet, acknowledging it. `None` once GDB disconnects
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    loop {
+        // Skip acknowledgements and stray interrupts until a packet starts
+        match read_byte(stream)? {
+            Some(b'$') => (),
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+        let mut sent_checksum = [0; 2];
+        stream.read_exact(&mut sent_checksum)?;
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let valid = std::str::from_utf8(&sent_checksum)
+            .ok()
+            .and_then(|sent| u8::from_str_radix(sent, 16).ok())
+            == Some(checksum(&data));
+
+        if valid {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        // Ask for the packet again
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
@@ -1,4 +1,7 @@
 use core::fmt;
+use std::str::FromStr;
+
+use crate::Error;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Reg {
@@ -31,6 +34,40 @@ impl fmt::Display for Reg {
     }
 }
 
+impl FromStr for Reg {
+    type Err = Error;
+
+    /// Parse a register as `Display` writes it, in any case
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match text.to_ascii_uppercase().as_str() {
+            "A" => Reg::A,
+            "B" => Reg::B,
+            "C" => Reg::C,
+            "D" => Reg::D,
+            "E" => Reg::E,
+            "SP" => Reg::SP,
+            "H" => Reg::H,
+            "L" => Reg::L,
+            "PSW" => Reg::Psw,
+            "M" => Reg::M,
+            _ => return Err(Error::InvalidSyntax(format!("{} isn't a register", text))),
+        })
+    }
+}
+
+/// The conditions tested by the conditional jump, call and return instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Condition {
+    NotZero,
+    Zero,
+    NoCarry,
+    Carry,
+    ParityOdd,
+    ParityEven,
+    Plus,
+    Minus,
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Instruction {
     NoOp,
@@ -116,6 +153,13 @@ pub enum Instruction {
     EI,
     CM { address: u16 },
     CPI { data: u8 },
+    // Undocumented aliases. The 8080 doesn't fully decode these opcodes, so
+    // they behave like the documented instruction they share bits with.
+    // `opcode` records which alias was used where there is more than one
+    UndocumentedNoOp { opcode: u8 },
+    UndocumentedJMP { address: u16 },
+    UndocumentedRET,
+    UndocumentedCALL { opcode: u8, address: u16 },
 }
 
 impl fmt::Display for Instruction {
@@ -202,10 +246,28 @@ impl fmt::Display for Instruction {
             Instruction::CM { address } => write!(f, "CM {:#06x}", address),
             Instruction::CPI { data } => write!(f, "CPI {:#04x}", data),
             Instruction::CMC => write!(f, "CMC"),
+            // Undocumented instructions are marked with a `*`
+            Instruction::UndocumentedNoOp { opcode } => write!(f, "*NOP ({:#04x})", opcode),
+            Instruction::UndocumentedJMP { address } => write!(f, "*JMP {:#06x}", address),
+            Instruction::UndocumentedRET => write!(f, "*RET"),
+            Instruction::UndocumentedCALL { opcode, address } => {
+                write!(f, "*CALL {:#06x} ({:#04x})", address, opcode)
+            }
         }
     }
 }
 
+impl FromStr for Instruction {
+    type Err = Error;
+
+    /// Parse an instruction as `Display` writes it (`MVI B 0x14`), or in
+    /// Intel syntax (`MVI B,14H`). Operands can be any expression the
+    /// assembler accepts that doesn't use symbols
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        crate::assembler::parse_instruction(text).map_err(Error::InvalidSyntax)
+    }
+}
+
 impl Instruction {
     pub fn op_bytes(&self) -> u8 {
         match self {
@@ -296,17 +358,259 @@ impl Instruction {
             Instruction::EI => 1,
             Instruction::CM { address: _ } => 3,
             Instruction::CPI { data: _ } => 2,
+            Instruction::UndocumentedNoOp { opcode: _ } => 1,
+            Instruction::UndocumentedJMP { address: _ } => 3,
+            Instruction::UndocumentedRET => 1,
+            Instruction::UndocumentedCALL {
+                opcode: _,
+                address: _,
+            } => 3,
+        }
+    }
+
+    /// The number of clock cycles (T-states) the instruction takes. For
+    /// conditional calls and returns this is the cost when the condition
+    /// doesn't hold, see `cycles_taken`
+    pub fn cycles(&self) -> u8 {
+        match self {
+            Instruction::NoOp | Instruction::UndocumentedNoOp { .. } => 4,
+            Instruction::LXI { .. } => 10,
+            Instruction::STAX { .. } | Instruction::LDAX { .. } => 7,
+            Instruction::INX { .. } | Instruction::DCX { .. } => 5,
+            Instruction::INR { register } | Instruction::DCR { register } => {
+                if *register == Reg::M {
+                    10
+                } else {
+                    5
+                }
+            }
+            Instruction::MVI { register, .. } => {
+                if *register == Reg::M {
+                    10
+                } else {
+                    7
+                }
+            }
+            Instruction::RLC
+            | Instruction::RRC
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::DAA
+            | Instruction::CMA
+            | Instruction::STC
+            | Instruction::CMC => 4,
+            Instruction::DAD { .. } => 10,
+            Instruction::SHLD { .. } | Instruction::LHLD { .. } => 16,
+            Instruction::STA { .. } | Instruction::LDA { .. } => 13,
+            Instruction::MOV {
+                source,
+                destination,
+            } => {
+                if *source == Reg::M || *destination == Reg::M {
+                    7
+                } else {
+                    5
+                }
+            }
+            Instruction::HLT => 7,
+            Instruction::ADD { register }
+            | Instruction::ADC { register }
+            | Instruction::SUB { register }
+            | Instruction::SBB { register }
+            | Instruction::ANA { register }
+            | Instruction::XRA { register }
+            | Instruction::ORA { register }
+            | Instruction::CMP { register } => {
+                if *register == Reg::M {
+                    7
+                } else {
+                    4
+                }
+            }
+            Instruction::ADI { .. }
+            | Instruction::ACI { .. }
+            | Instruction::SUI { .. }
+            | Instruction::SBI { .. }
+            | Instruction::ANI { .. }
+            | Instruction::XRI { .. }
+            | Instruction::ORI { .. }
+            | Instruction::CPI { .. } => 7,
+            Instruction::RNZ
+            | Instruction::RZ
+            | Instruction::RNC
+            | Instruction::RC
+            | Instruction::RPO
+            | Instruction::RPE
+            | Instruction::RP
+            | Instruction::RM => 5,
+            Instruction::RET | Instruction::UndocumentedRET => 10,
+            Instruction::JNZ { .. }
+            | Instruction::JZ { .. }
+            | Instruction::JNC { .. }
+            | Instruction::JC { .. }
+            | Instruction::JPO { .. }
+            | Instruction::JPE { .. }
+            | Instruction::JP { .. }
+            | Instruction::JM { .. }
+            | Instruction::JMP { .. }
+            | Instruction::UndocumentedJMP { .. } => 10,
+            Instruction::CNZ { .. }
+            | Instruction::CZ { .. }
+            | Instruction::CNC { .. }
+            | Instruction::CC { .. }
+            | Instruction::CPO { .. }
+            | Instruction::CPE { .. }
+            | Instruction::CP { .. }
+            | Instruction::CM { .. } => 11,
+            Instruction::CALL { .. } | Instruction::UndocumentedCALL { .. } => 17,
+            Instruction::POP { .. } => 10,
+            Instruction::PUSH { .. } => 11,
+            Instruction::RST { .. } => 11,
+            Instruction::OUT { .. } | Instruction::IN { .. } => 10,
+            Instruction::XTHL => 18,
+            Instruction::PCHL | Instruction::SPHL => 5,
+            Instruction::XCHG | Instruction::DI | Instruction::EI => 4,
         }
     }
 
-    pub(crate) fn decode(bin: &[u8]) -> Option<Self> {
-        let val_one = bin.get(0);
+    /// The number of clock cycles (T-states) the instruction takes when its
+    /// condition holds. This only differs from `cycles` for conditional
+    /// calls and returns, which take 6 more cycles to push or pop the pc
+    pub fn cycles_taken(&self) -> u8 {
+        match self {
+            Instruction::RNZ
+            | Instruction::RZ
+            | Instruction::RNC
+            | Instruction::RC
+            | Instruction::RPO
+            | Instruction::RPE
+            | Instruction::RP
+            | Instruction::RM
+            | Instruction::CNZ { .. }
+            | Instruction::CZ { .. }
+            | Instruction::CNC { .. }
+            | Instruction::CC { .. }
+            | Instruction::CPO { .. }
+            | Instruction::CPE { .. }
+            | Instruction::CP { .. }
+            | Instruction::CM { .. } => self.cycles() + 6,
+            _ => self.cycles(),
+        }
+    }
+
+    /// The condition tested by a conditional jump, call or return
+    pub(crate) fn condition(&self) -> Option<Condition> {
+        match self {
+            Instruction::RNZ | Instruction::JNZ { .. } | Instruction::CNZ { .. } => {
+                Some(Condition::NotZero)
+            }
+            Instruction::RZ | Instruction::JZ { .. } | Instruction::CZ { .. } => {
+                Some(Condition::Zero)
+            }
+            Instruction::RNC | Instruction::JNC { .. } | Instruction::CNC { .. } => {
+                Some(Condition::NoCarry)
+            }
+            Instruction::RC | Instruction::JC { .. } | Instruction::CC { .. } => {
+                Some(Condition::Carry)
+            }
+            Instruction::RPO | Instruction::JPO { .. } | Instruction::CPO { .. } => {
+                Some(Condition::ParityOdd)
+            }
+            Instruction::RPE | Instruction::JPE { .. } | Instruction::CPE { .. } => {
+                Some(Condition::ParityEven)
+            }
+            Instruction::RP | Instruction::JP { .. } | Instruction::CP { .. } => {
+                Some(Condition::Plus)
+            }
+            Instruction::RM | Instruction::JM { .. } | Instruction::CM { .. } => {
+                Some(Condition::Minus)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this is one of the undocumented opcode aliases
+    pub fn is_undocumented(&self) -> bool {
+        matches!(
+            self,
+            Instruction::UndocumentedNoOp { .. }
+                | Instruction::UndocumentedJMP { .. }
+                | Instruction::UndocumentedRET
+                | Instruction::UndocumentedCALL { .. }
+        )
+    }
+
+    /// Where a jump, call or RST goes when taken. PCHL's target isn't known
+    /// until it executes, so it has none
+    pub(crate) fn branch_target(&self) -> Option<u16> {
+        match *self {
+            Instruction::JMP { address }
+            | Instruction::JNZ { address }
+            | Instruction::JZ { address }
+            | Instruction::JNC { address }
+            | Instruction::JC { address }
+            | Instruction::JPO { address }
+            | Instruction::JPE { address }
+            | Instruction::JP { address }
+            | Instruction::JM { address }
+            | Instruction::UndocumentedJMP { address }
+            | Instruction::CALL { address }
+            | Instruction::CNZ { address }
+            | Instruction::CZ { address }
+            | Instruction::CNC { address }
+            | Instruction::CC { address }
+            | Instruction::CPO { address }
+            | Instruction::CPE { address }
+            | Instruction::CP { address }
+            | Instruction::CM { address }
+            | Instruction::UndocumentedCALL { address, .. } => Some(address),
+            Instruction::RST { data } => Some(u16::from(data & 0b111) * 8),
+            _ => None,
+        }
+    }
+
+    /// The 16 bit address or value the instruction was given, which could
+    /// be named by a symbol
+    pub(crate) fn address_operand(&self) -> Option<u16> {
+        match *self {
+            Instruction::RST { .. } => None,
+            Instruction::LXI { value, .. } => Some(value),
+            Instruction::SHLD { address }
+            | Instruction::LHLD { address }
+            | Instruction::STA { address }
+            | Instruction::LDA { address } => Some(address),
+            _ => self.branch_target(),
+        }
+    }
+
+    /// Whether execution can carry on to the next instruction, which it
+    /// can't after an unconditional jump or return
+    pub(crate) fn falls_through(&self) -> bool {
+        !matches!(
+            self,
+            Instruction::JMP { .. }
+                | Instruction::UndocumentedJMP { .. }
+                | Instruction::PCHL
+                | Instruction::RET
+                | Instruction::UndocumentedRET
+        )
+    }
+
+    /// The length in bytes of the instruction starting with `opcode`
+    pub(crate) fn length(opcode: u8) -> u8 {
+        Self::decode(&[opcode, 0, 0])
+            .map(|instruction| instruction.op_bytes())
+            .unwrap_or(1)
+    }
+
+    pub(crate) fn decode(bin: &[u8]) -> Result<Self, DecodeError> {
+        let val_one = bin.first();
         if let Some(val_one) = val_one {
-            Some(match val_one {
+            Ok(match val_one {
                 0x00 => Instruction::NoOp,
                 0x01 => Instruction::LXI {
                     register: Reg::B,
-                    value: address_from_slice(&bin[1..]),
+                    value: address_from_slice(bin)?,
                 },
                 0x02 => Instruction::STAX { register: Reg::B },
                 0x03 => Instruction::INX { register: Reg::B },
@@ -314,10 +618,10 @@ impl Instruction {
                 0x05 => Instruction::DCR { register: Reg::B },
                 0x06 => Instruction::MVI {
                     register: Reg::B,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x07 => Instruction::RLC,
-                0x08 => return None,
+                0x08 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x09 => Instruction::DAD { register: Reg::B },
                 0x0a => Instruction::LDAX { register: Reg::B },
                 0x0b => Instruction::DCX { register: Reg::B },
@@ -325,13 +629,13 @@ impl Instruction {
                 0x0d => Instruction::DCR { register: Reg::C },
                 0x0e => Instruction::MVI {
                     register: Reg::C,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x0f => Instruction::RRC,
-                0x10 => return None,
+                0x10 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x11 => Instruction::LXI {
                     register: Reg::D,
-                    value: address_from_slice(&bin[1..]),
+                    value: address_from_slice(bin)?,
                 },
                 0x12 => Instruction::STAX { register: Reg::D },
                 0x13 => Instruction::INX { register: Reg::D },
@@ -339,10 +643,10 @@ impl Instruction {
                 0x15 => Instruction::DCR { register: Reg::D },
                 0x16 => Instruction::MVI {
                     register: Reg::D,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x17 => Instruction::RAL,
-                0x18 => return None,
+                0x18 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x19 => Instruction::DAD { register: Reg::D },
                 0x1a => Instruction::LDAX { register: Reg::D },
                 0x1b => Instruction::DCX { register: Reg::D },
@@ -350,65 +654,65 @@ impl Instruction {
                 0x1d => Instruction::DCR { register: Reg::E },
                 0x1e => Instruction::MVI {
                     register: Reg::E,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x1f => Instruction::RAR,
-                0x20 => return None,
+                0x20 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x21 => Instruction::LXI {
                     register: Reg::H,
-                    value: address_from_slice(&bin[1..]),
+                    value: address_from_slice(bin)?,
                 },
                 0x22 => Instruction::SHLD {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0x23 => Instruction::INX { register: Reg::H },
                 0x24 => Instruction::INR { register: Reg::H },
                 0x25 => Instruction::DCR { register: Reg::H },
                 0x26 => Instruction::MVI {
                     register: Reg::H,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x27 => Instruction::DAA,
-                0x28 => return None,
+                0x28 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x29 => Instruction::DAD { register: Reg::H },
                 0x2a => Instruction::LHLD {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0x2b => Instruction::DCX { register: Reg::H },
                 0x2c => Instruction::INR { register: Reg::L },
                 0x2d => Instruction::DCR { register: Reg::L },
                 0x2e => Instruction::MVI {
                     register: Reg::L,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x2f => Instruction::CMA,
-                0x30 => return None,
+                0x30 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x31 => Instruction::LXI {
                     register: Reg::SP,
-                    value: address_from_slice(&bin[1..]),
+                    value: address_from_slice(bin)?,
                 },
                 0x32 => Instruction::STA {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0x33 => Instruction::INX { register: Reg::SP },
                 0x34 => Instruction::INR { register: Reg::M },
                 0x35 => Instruction::DCR { register: Reg::M },
                 0x36 => Instruction::MVI {
                     register: Reg::M,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x37 => Instruction::STC,
-                0x38 => return None,
+                0x38 => Instruction::UndocumentedNoOp { opcode: *val_one },
                 0x39 => Instruction::DAD { register: Reg::SP },
                 0x3a => Instruction::LDA {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0x3b => Instruction::DCX { register: Reg::SP },
                 0x3c => Instruction::INR { register: Reg::A },
                 0x3d => Instruction::DCR { register: Reg::A },
                 0x3e => Instruction::MVI {
                     register: Reg::A,
-                    value: *bin.get(1)?,
+                    value: byte_from_slice(bin)?,
                 },
                 0x3f => Instruction::CMC,
                 0x40 => Instruction::MOV {
@@ -492,7 +796,7 @@ impl Instruction {
                     destination: Reg::D,
                 },
                 0x54 => Instruction::MOV {
-                    source: Reg::L,
+                    source: Reg::H,
                     destination: Reg::D,
                 },
                 0x55 => Instruction::MOV {
@@ -512,8 +816,8 @@ impl Instruction {
                     destination: Reg::E,
                 },
                 0x59 => Instruction::MOV {
-                    source: Reg::E,
-                    destination: Reg::C,
+                    source: Reg::C,
+                    destination: Reg::E,
                 },
                 0x5a => Instruction::MOV {
                     source: Reg::D,
@@ -544,8 +848,8 @@ impl Instruction {
                     destination: Reg::H,
                 },
                 0x61 => Instruction::MOV {
-                    source: Reg::H,
-                    destination: Reg::C,
+                    source: Reg::C,
+                    destination: Reg::H,
                 },
                 0x62 => Instruction::MOV {
                     source: Reg::D,
@@ -731,110 +1035,298 @@ impl Instruction {
                 0xc0 => Instruction::RNZ, //  1    if NZ, RET
                 0xc1 => Instruction::POP { register: Reg::B },
                 0xc2 => Instruction::JNZ {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xc3 => Instruction::JMP {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xc4 => Instruction::CNZ {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xc5 => Instruction::PUSH { register: Reg::B },
-                0xc6 => Instruction::ADI { data: *bin.get(1)? },
+                0xc6 => Instruction::ADI {
+                    data: byte_from_slice(bin)?,
+                },
                 0xc7 => Instruction::RST { data: 0 }, // 0  1    CALL $0
                 0xc8 => Instruction::RZ,              //  1    if Z, RET
                 0xc9 => Instruction::RET, //  1    PC.lo <- (sp); PC.hi<-(sp+1); SP <- SP+2
                 0xca => Instruction::JZ {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xcb => Instruction::UndocumentedJMP {
+                    address: address_from_slice(bin)?,
                 },
-                0xcb => return None,
                 0xcc => Instruction::CZ {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xcd => Instruction::CALL {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xce => Instruction::ACI {
+                    data: byte_from_slice(bin)?,
                 },
-                0xce => Instruction::ACI { data: *bin.get(1)? },
                 0xcf => Instruction::RST { data: 1 },
                 0xd0 => Instruction::RNC,
                 0xd1 => Instruction::POP { register: Reg::D },
                 0xd2 => Instruction::JNC {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xd3 => Instruction::OUT {
+                    data: byte_from_slice(bin)?,
                 },
-                0xd3 => Instruction::OUT { data: *bin.get(1)? },
                 0xd4 => Instruction::CNC {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xd5 => Instruction::PUSH { register: Reg::D },
-                0xd6 => Instruction::SUI { data: *bin.get(1)? },
+                0xd6 => Instruction::SUI {
+                    data: byte_from_slice(bin)?,
+                },
                 0xd7 => Instruction::RST { data: 2 },
                 0xd8 => Instruction::RC,
-                0xd9 => return None,
+                0xd9 => Instruction::UndocumentedRET,
                 0xda => Instruction::JC {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xdb => Instruction::IN {
+                    data: byte_from_slice(bin)?,
                 },
-                0xdb => Instruction::IN { data: *bin.get(1)? },
                 0xdc => Instruction::CC {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xdd => Instruction::UndocumentedCALL {
+                    opcode: *val_one,
+                    address: address_from_slice(bin)?,
+                },
+                0xde => Instruction::SBI {
+                    data: byte_from_slice(bin)?,
                 },
-                0xdd => return None,
-                0xde => Instruction::SBI { data: *bin.get(1)? },
                 0xdf => Instruction::RST { data: 3 },
                 0xe0 => Instruction::RPO,
                 0xe1 => Instruction::POP { register: Reg::H },
                 0xe2 => Instruction::JPO {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xe3 => Instruction::XTHL,
                 0xe4 => Instruction::CPO {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xe5 => Instruction::PUSH { register: Reg::H },
-                0xe6 => Instruction::ANI { data: *bin.get(1)? },
+                0xe6 => Instruction::ANI {
+                    data: byte_from_slice(bin)?,
+                },
                 0xe7 => Instruction::RST { data: 4 },
                 0xe8 => Instruction::RPE,
                 0xe9 => Instruction::PCHL,
                 0xea => Instruction::JPE {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xeb => Instruction::XCHG,
                 0xec => Instruction::CPE {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xed => Instruction::UndocumentedCALL {
+                    opcode: *val_one,
+                    address: address_from_slice(bin)?,
+                },
+                0xee => Instruction::XRI {
+                    data: byte_from_slice(bin)?,
                 },
-                0xed => return None,
-                0xee => Instruction::XRI { data: *bin.get(1)? },
                 0xef => Instruction::RST { data: 5 },
                 0xf0 => Instruction::RP,
                 0xf1 => Instruction::POP { register: Reg::Psw },
                 0xf2 => Instruction::JP {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xf3 => Instruction::DI,
                 0xf4 => Instruction::CP {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xf5 => Instruction::PUSH { register: Reg::Psw },
-                0xf6 => Instruction::ORI { data: *bin.get(1)? },
+                0xf6 => Instruction::ORI {
+                    data: byte_from_slice(bin)?,
+                },
                 0xf7 => Instruction::RST { data: 6 },
                 0xf8 => Instruction::RM,
                 0xf9 => Instruction::SPHL,
                 0xfa => Instruction::JM {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
                 },
                 0xfb => Instruction::EI,
                 0xfc => Instruction::CM {
-                    address: address_from_slice(&bin[1..]),
+                    address: address_from_slice(bin)?,
+                },
+                0xfd => Instruction::UndocumentedCALL {
+                    opcode: *val_one,
+                    address: address_from_slice(bin)?,
+                },
+                0xfe => Instruction::CPI {
+                    data: byte_from_slice(bin)?,
                 },
-                0xfd => return None,
-                0xfe => Instruction::CPI { data: *bin.get(1)? },
                 0xff => Instruction::RST { data: 7 },
             })
         } else {
-            None
+            Err(DecodeError::Empty)
         }
     }
+
+    /// The bytes of the instruction, the inverse of decoding. Fails if a
+    /// register can't be used with the instruction, like `LXI A`
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        use Instruction::*;
+
+        Ok(match *self {
+            NoOp => vec![0x00],
+            LXI { register, value } => {
+                with_address(0x01 | pair_code(register, Some(Reg::SP))? << 4, value)
+            }
+            STAX { register } => vec![0x02 | pair_code(register, None)? << 4],
+            INX { register } => vec![0x03 | pair_code(register, Some(Reg::SP))? << 4],
+            INR { register } => vec![0x04 | register_code(register)? << 3],
+            DCR { register } => vec![0x05 | register_code(register)? << 3],
+            MVI { register, value } => vec![0x06 | register_code(register)? << 3, value],
+            RLC => vec![0x07],
+            DAD { register } => vec![0x09 | pair_code(register, Some(Reg::SP))? << 4],
+            LDAX { register } => vec![0x0a | pair_code(register, None)? << 4],
+            DCX { register } => vec![0x0b | pair_code(register, Some(Reg::SP))? << 4],
+            RRC => vec![0x0f],
+            RAL => vec![0x17],
+            RAR => vec![0x1f],
+            SHLD { address } => with_address(0x22, address),
+            DAA => vec![0x27],
+            LHLD { address } => with_address(0x2a, address),
+            CMA => vec![0x2f],
+            STA { address } => with_address(0x32, address),
+            STC => vec![0x37],
+            LDA { address } => with_address(0x3a, address),
+            CMC => vec![0x3f],
+            // MOV M, M would be HLT
+            MOV {
+                source: Reg::M,
+                destination: Reg::M,
+            } => return Err(Error::InvalidRegister(Reg::M)),
+            MOV {
+                source,
+                destination,
+            } => vec![0x40 | register_code(destination)? << 3 | register_code(source)?],
+            HLT => vec![0x76],
+            ADD { register } => vec![0x80 | register_code(register)?],
+            ADC { register } => vec![0x88 | register_code(register)?],
+            SUB { register } => vec![0x90 | register_code(register)?],
+            SBB { register } => vec![0x98 | register_code(register)?],
+            ANA { register } => vec![0xa0 | register_code(register)?],
+            XRA { register } => vec![0xa8 | register_code(register)?],
+            ORA { register } => vec![0xb0 | register_code(register)?],
+            CMP { register } => vec![0xb8 | register_code(register)?],
+            RNZ => vec![0xc0],
+            POP { register } => vec![0xc1 | pair_code(register, Some(Reg::Psw))? << 4],
+            JNZ { address } => with_address(0xc2, address),
+            JMP { address } => with_address(0xc3, address),
+            CNZ { address } => with_address(0xc4, address),
+            PUSH { register } => vec![0xc5 | pair_code(register, Some(Reg::Psw))? << 4],
+            ADI { data } => vec![0xc6, data],
+            RST { data } => vec![0xc7 | (data & 0b111) << 3],
+            RZ => vec![0xc8],
+            RET => vec![0xc9],
+            JZ { address } => with_address(0xca, address),
+            CZ { address } => with_address(0xcc, address),
+            CALL { address } => with_address(0xcd, address),
+            ACI { data } => vec![0xce, data],
+            RNC => vec![0xd0],
+            JNC { address } => with_address(0xd2, address),
+            OUT { data } => vec![0xd3, data],
+            CNC { address } => with_address(0xd4, address),
+            SUI { data } => vec![0xd6, data],
+            RC => vec![0xd8],
+            JC { address } => with_address(0xda, address),
+            IN { data } => vec![0xdb, data],
+            CC { address } => with_address(0xdc, address),
+            SBI { data } => vec![0xde, data],
+            RPO => vec![0xe0],
+            JPO { address } => with_address(0xe2, address),
+            XTHL => vec![0xe3],
+            CPO { address } => with_address(0xe4, address),
+            ANI { data } => vec![0xe6, data],
+            RPE => vec![0xe8],
+            PCHL => vec![0xe9],
+            JPE { address } => with_address(0xea, address),
+            XCHG => vec![0xeb],
+            CPE { address } => with_address(0xec, address),
+            XRI { data } => vec![0xee, data],
+            RP => vec![0xf0],
+            JP { address } => with_address(0xf2, address),
+            DI => vec![0xf3],
+            CP { address } => with_address(0xf4, address),
+            ORI { data } => vec![0xf6, data],
+            RM => vec![0xf8],
+            SPHL => vec![0xf9],
+            JM { address } => with_address(0xfa, address),
+            EI => vec![0xfb],
+            CM { address } => with_address(0xfc, address),
+            CPI { data } => vec![0xfe, data],
+            UndocumentedNoOp { opcode } => match opcode {
+                0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => vec![opcode],
+                _ => return Err(Error::InvalidAlias(opcode)),
+            },
+            UndocumentedJMP { address } => with_address(0xcb, address),
+            UndocumentedRET => vec![0xd9],
+            UndocumentedCALL { opcode, address } => match opcode {
+                0xdd | 0xed | 0xfd => with_address(opcode, address),
+                _ => return Err(Error::InvalidAlias(opcode)),
+            },
+        })
+    }
+}
+
+/// The three bit code for a register in an opcode
+fn register_code(register: Reg) -> Result<u8, Error> {
+    match register {
+        Reg::B => Ok(0),
+        Reg::C => Ok(1),
+        Reg::D => Ok(2),
+        Reg::E => Ok(3),
+        Reg::H => Ok(4),
+        Reg::L => Ok(5),
+        Reg::M => Ok(6),
+        Reg::A => Ok(7),
+        _ => Err(Error::InvalidRegister(register)),
+    }
 }
 
-fn address_from_slice(slice: &[u8]) -> u16 {
-    ((slice[1] as u16) << 8) + (slice[0] as u16)
+/// The two bit code for a register pair in an opcode. `fourth` is the pair
+/// the instruction uses for code 3 (SP or PSW), if it has one
+fn pair_code(register: Reg, fourth: Option<Reg>) -> Result<u8, Error> {
+    match register {
+        Reg::B => Ok(0),
+        Reg::D => Ok(1),
+        Reg::H => Ok(2),
+        _ if Some(register) == fourth => Ok(3),
+        _ => Err(Error::InvalidRegister(register)),
+    }
+}
+
+fn with_address(opcode: u8, address: u16) -> Vec<u8> {
+    let [low, high] = address.to_le_bytes();
+    vec![opcode, low, high]
+}
+
+/// Why a slice of bytes couldn't be decoded into an `Instruction`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DecodeError {
+    /// There were no bytes to decode
+    Empty,
+    /// The slice ends before the operands of the opcode
+    Truncated(u8),
+}
+
+/// The single byte operand of the instruction at the start of `bin`
+fn byte_from_slice(bin: &[u8]) -> Result<u8, DecodeError> {
+    bin.get(1).copied().ok_or(DecodeError::Truncated(bin[0]))
+}
+
+/// The little endian address operand of the instruction at the start of `bin`
+fn address_from_slice(bin: &[u8]) -> Result<u16, DecodeError> {
+    match bin {
+        [_, low, high, ..] => Ok(((*high as u16) << 8) + (*low as u16)),
+        _ => Err(DecodeError::Truncated(bin[0])),
+    }
 }
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::{Error, MemoryBus};
+
+/// What's wrong with a line of Intel HEX, see `Error::InvalidIntelHex`
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntelHexError {
+    /// The line doesn't start with `:`
+    MissingColon,
+    /// The character at `column` (counting from 1) isn't a hex digit
+    InvalidDigit {
+        column: usize,
+    },
+    /// The line has an odd number of digits or is shorter than a record
+    Truncated,
+    /// The byte count doesn't match the length of the record
+    WrongLength {
+        count: u8,
+        actual: usize,
+    },
+    Checksum {
+        expected: u8,
+        actual: u8,
+    },
+    /// A record type other than data, EOF or start address
+    UnsupportedRecord(u8),
+    /// A start address or EOF record with the wrong length of data
+    InvalidRecord(u8),
+    /// A data record runs past 0xffff, or a start address doesn't fit in 16
+    /// bits
+    AddressOutOfRange,
+    /// There's a record after the EOF record
+    AfterEof,
+    /// The text ends without an EOF record
+    MissingEof,
+}
+
+impl fmt::Display for IntelHexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntelHexError::MissingColon => write!(f, "record doesn't start with ':'"),
+            IntelHexError::InvalidDigit { column } => {
+                write!(f, "invalid hex digit in column {}", column)
+            }
+            IntelHexError::Truncated => write!(f, "record is truncated"),
+            IntelHexError::WrongLength { count, actual } => write!(
+                f,
+                "byte count is {} but the record has {} data bytes",
+                count, actual
+            ),
+            IntelHexError::Checksum { expected, actual } => write!(
+                f,
+                "checksum is {:#04x} but should be {:#04x}",
+                actual, expected
+            ),
+            IntelHexError::UnsupportedRecord(kind) => {
+                write!(f, "unsupported record type {:02X}", kind)
+            }
+            IntelHexError::InvalidRecord(kind) => {
+                write!(f, "record type {:02X} has the wrong length", kind)
+            }
+            IntelHexError::AddressOutOfRange => write!(f, "address is out of range"),
+            IntelHexError::AfterEof => write!(f, "record after the end of file"),
+            IntelHexError::MissingEof => write!(f, "no end of file record"),
+        }
+    }
+}
+
+/// The contents of an Intel HEX file: runs of bytes and where they go, and
+/// the start address if there is one
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IntelHex {
+    /// Consecutive data records are joined into one run
+    pub data: Vec<(u16, Vec<u8>)>,
+    pub start: Option<u16>,
+}
+
+/// Bytes per data record written by `IntelHex::to_string`
+const RECORD_SIZE: usize = 16;
+
+const DATA: u8 = 0x00;
+const EOF: u8 = 0x01;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+impl IntelHex {
+    /// Parse data (00), end of file (01) and start address (03 and 05)
+    /// records. Blank lines are ignored
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut hex = IntelHex::default();
+        let mut ended = false;
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |error| Error::InvalidIntelHex {
+                line: index + 1,
+                error,
+            };
+            if ended {
+                return Err(error(IntelHexError::AfterEof));
+            }
+
+            let (kind, address, data) = parse_record(line).map_err(error)?;
+            match kind {
+                DATA => hex.add_data(address, data).map_err(error)?,
+                EOF if data.is_empty() => ended = true,
+                START_SEGMENT_ADDRESS if data.len() == 4 => {
+                    let segment = u32::from(u16::from_be_bytes([data[0], data[1]]));
+                    let offset = u32::from(u16::from_be_bytes([data[2], data[3]]));
+                    hex.start = Some(start_address((segment << 4) + offset).map_err(error)?);
+                }
+                START_LINEAR_ADDRESS if data.len() == 4 => {
+                    let address = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                    hex.start = Some(start_address(address).map_err(error)?);
+                }
+                EOF | START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {
+                    return Err(error(IntelHexError::InvalidRecord(kind)))
+                }
+                kind => return Err(error(IntelHexError::UnsupportedRecord(kind))),
+            }
+        }
+
+        if !ended {
+            return Err(Error::InvalidIntelHex {
+                line: text.lines().count(),
+                error: IntelHexError::MissingEof,
+            });
+        }
+        Ok(hex)
+    }
+
+    /// The bytes in `ranges` of `memory`, read with `peek`
+    pub fn from_memory<M: MemoryBus>(
+        memory: &M,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        start: Option<u16>,
+    ) -> Self {
+        let data = ranges
+            .into_iter()
+            .map(|range| {
+                let bytes = range.clone().map(|address| memory.peek(address)).collect();
+                (*range.start(), bytes)
+            })
+            .collect();
+        IntelHex { data, start }
+    }
+
+    fn add_data(&mut self, address: u16, data: Vec<u8>) -> Result<(), IntelHexError> {
+        if usize::from(address) + data.len() > 0x10000 {
+            return Err(IntelHexError::AddressOutOfRange);
+        }
+        match self.data.last_mut() {
+            Some((start, run)) if usize::from(*start) + run.len() == usize::from(address) => {
+                run.extend(data)
+            }
+            _ => self.data.push((address, data)),
+        }
+        Ok(())
+    }
+}
+
+/// Writes data records of up to 16 bytes, a start segment address record if
+/// there's a start address, and the EOF record
+impl fmt::Display for IntelHex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (start, bytes) in &self.data {
+            for (index, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+                let address = start.wrapping_add((index * RECORD_SIZE) as u16);
+                write_record(f, DATA, address, chunk)?;
+            }
+        }
+        if let Some(start) = self.start {
+            let [high, low] = start.to_be_bytes();
+            write_record(f, START_SEGMENT_ADDRESS, 0, &[0, 0, high, low])?;
+        }
+        write_record(f, EOF, 0, &[])
+    }
+}
+
+fn write_record(f: &mut fmt::Formatter<'_>, kind: u8, address: u16, data: &[u8]) -> fmt::Result {
+    let [high, low] = address.to_be_bytes();
+    let mut record = vec![data.len() as u8, high, low, kind];
+    record.extend(data);
+    record.push(checksum(&record));
+
+    write!(f, ":")?;
+    for byte in record {
+        write!(f, "{:02X}", byte)?;
+    }
+    writeln!(f)
+}
+
+/// The byte that makes the record sum to zero
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg()
+}
+
+fn start_address(address: u32) -> Result<u16, IntelHexError> {
+    u16::try_from(address).map_err(|_| IntelHexError::AddressOutOfRange)
+}
+
+/// The type, address and data of a record
+fn parse_record(line: &str) -> Result<(u8, u16, Vec<u8>), IntelHexError> {
+    let digits = line.strip_prefix(':').ok_or(IntelHexError::MissingColon)?;
+    if let Some(index) = digits.find(|c: char| !c.is_ascii_hexdigit()) {
+        return Err(IntelHexError::InvalidDigit { column: index + 2 });
+    }
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return Err(IntelHexError::Truncated);
+    }
+
+    let bytes: Vec<u8> = (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).unwrap())
+        .collect();
+    let (record, [actual]) = bytes.split_at(bytes.len() - 1) else {
+        unreachable!("there are at least five bytes");
+    };
+
+    let count = record[0];
+    let data = &record[4..];
+    if usize::from(count) != data.len() {
+        return Err(IntelHexError::WrongLength {
+            count,
+            actual: data.len(),
+        });
+    }
+    let expected = checksum(record);
+    if expected != *actual {
+        return Err(IntelHexError::Checksum {
+            expected,
+            actual: *actual,
+        });
+    }
+
+    Ok((
+        record[3],
+        u16::from_be_bytes([record[1], record[2]]),
+        data.to_vec(),
+    ))
+}
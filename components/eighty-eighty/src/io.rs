@@ -0,0 +1,62 @@
+/// The devices attached to the 8080's 256 input and output ports
+///
+/// Any `FnMut(port, value)` closure can be used where only OUT matters; IN
+/// then reads back whatever is latched on the data bus. Use [`Ports`] to
+/// supply separate handlers for both directions.
+pub trait Io {
+    /// Called when IN is executed. `bus` is the value currently latched on
+    /// the data bus, which is what a read from an unconnected port yields.
+    /// The returned value is loaded into (A)
+    fn input(&mut self, port: u8, bus: u8) -> u8;
+
+    /// Called when OUT is executed, with the contents of (A)
+    fn output(&mut self, port: u8, value: u8);
+}
+
+impl<F: FnMut(u8, u8)> Io for F {
+    fn input(&mut self, _port: u8, bus: u8) -> u8 {
+        bus
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        self(port, value)
+    }
+}
+
+/// Separate handlers for the IN and OUT instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ports<In, Out>
+where
+    In: FnMut(u8, u8) -> u8,
+    Out: FnMut(u8, u8),
+{
+    on_input: In,
+    on_output: Out,
+}
+
+impl<In, Out> Ports<In, Out>
+where
+    In: FnMut(u8, u8) -> u8,
+    Out: FnMut(u8, u8),
+{
+    pub fn new(on_input: In, on_output: Out) -> Self {
+        Self {
+            on_input,
+            on_output,
+        }
+    }
+}
+
+impl<In, Out> Io for Ports<In, Out>
+where
+    In: FnMut(u8, u8) -> u8,
+    Out: FnMut(u8, u8),
+{
+    fn input(&mut self, port: u8, bus: u8) -> u8 {
+        (self.on_input)(port, bus)
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        (self.on_output)(port, value)
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use crate::{cpu::ConditionCodes, Instruction};
+
+/// Everything a step can change apart from memory
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Registers {
+    pub(crate) a: u8,
+    pub(crate) b: u8,
+    pub(crate) c: u8,
+    pub(crate) d: u8,
+    pub(crate) e: u8,
+    pub(crate) h: u8,
+    pub(crate) l: u8,
+    pub(crate) pc: u16,
+    pub(crate) sp: u16,
+    pub(crate) condition_codes: ConditionCodes,
+    pub(crate) int_enable: bool,
+    pub(crate) interrupt_delay: bool,
+    pub(crate) halted: bool,
+    pub(crate) cycles: u64,
+}
+
+/// What's needed to undo one step
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JournalEntry {
+    /// The registers before the step
+    pub(crate) registers: Registers,
+    /// The interrupt request the step acknowledged, if any
+    pub(crate) interrupt: Option<Instruction>,
+    /// The addresses written by the step and the values they held, in the
+    /// order they were written
+    pub(crate) writes: Vec<(u16, u8)>,
+}
+
+/// An undo log of the most recent steps
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Journal {
+    limit: usize,
+    entries: VecDeque<JournalEntry>,
+    // Set while a step is being recorded into the newest entry
+    recording: bool,
+}
+
+impl Journal {
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Start recording a step taken from `registers`, dropping the oldest
+    /// entry if the journal is full
+    pub(crate) fn begin(&mut self, registers: Registers) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(JournalEntry {
+            registers,
+            interrupt: None,
+            writes: Vec::new(),
+        });
+        self.recording = true;
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.recording = false;
+    }
+
+    pub(crate) fn recording(&self) -> bool {
+        self.recording
+    }
+
+    pub(crate) fn record_write(&mut self, address: u16, previous: u8) {
+        if let Some(entry) = self.entries.back_mut().filter(|_| self.recording) {
+            entry.writes.push((address, previous));
+        }
+    }
+
+    pub(crate) fn record_interrupt(&mut self, instruction: Instruction) {
+        if let Some(entry) = self.entries.back_mut().filter(|_| self.recording) {
+            entry.interrupt = Some(instruction);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<JournalEntry> {
+        self.entries.pop_back()
+    }
+}
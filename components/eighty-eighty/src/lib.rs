@@ -2,29 +2,52 @@
 //! - Right tests to make sure that the register pair instructions are working
 //!   (I'm not sure whether I've done the endianness correctly)
 
+mod assembler;
 mod cpu;
+mod debugger;
+mod disassembler;
+mod gdb;
 mod instruction;
+mod intel_hex;
+mod io;
+mod journal;
+mod loader;
+mod macros;
+mod memory;
+mod save_state;
+mod symbols;
+mod trace;
 
 #[cfg(test)]
 mod tests;
 
+pub use assembler::{
+    assemble, assemble_file, Assembly, AssemblyError, Expansion, ExpansionKind, ListingLine,
+    SourceLocation,
+};
+
 pub use cpu::Error;
 
 pub use cpu::Cpu;
 
-pub use instruction::Instruction;
-
-pub fn disassemble(bin: Vec<u8>) {
-    let mut position = 0;
-
-    while position < bin.len() {
-        let instr = Instruction::decode(&bin[position..]);
-        if let Some(instr) = instr {
-            println!("{:#06x} {}", position, instr);
-            position += instr.op_bytes() as usize;
-        } else {
-            println!("{:#06x} NOP", position);
-            position += 1;
-        }
-    }
-}
+pub use debugger::{Debugger, InstructionClass, PortDirection, StopReason, WatchKind};
+
+pub use disassembler::{analyze, disassemble, Analysis, DisassembledLine, Disassembly};
+
+pub use gdb::{GdbConnection, GdbServer};
+
+pub use instruction::{Instruction, Reg};
+
+pub use intel_hex::{IntelHex, IntelHexError};
+
+pub use io::{Io, Ports};
+
+pub use loader::{Image, Segment};
+
+pub use memory::{AccessKind, Device, MappedMemory, Memory, MemoryAccess, MemoryBus};
+
+pub use save_state::{Flags, SaveState, SAVE_STATE_VERSION};
+
+pub use symbols::SymbolTable;
+
+pub use trace::{BinaryTraceWriter, RegisterState, TextTraceWriter, TraceRecord, TraceSink};
@@ -0,0 +1,74 @@
+use crate::memory::MEMORY_SIZE;
+use crate::Error;
+
+/// Bytes to be loaded at an address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    /// The address after the last byte, which is `MEMORY_SIZE` for a segment
+    /// that ends at 0xffff
+    fn end(&self) -> usize {
+        usize::from(self.address) + self.bytes.len()
+    }
+}
+
+/// A program made of segments, such as the ROM chips of an arcade board,
+/// and where to start running it. Load it with `Cpu::load_image`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    segments: Vec<Segment>,
+    entry: Option<u16>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `bytes` at `address`. Fails if they run past the end of memory or
+    /// overlap a segment that's already been added, in which case the error
+    /// has the first address they share
+    pub fn add_segment(&mut self, address: u16, bytes: Vec<u8>) -> Result<(), Error> {
+        let segment = Segment { address, bytes };
+        if segment.end() > MEMORY_SIZE {
+            return Err(Error::OutOfMemory);
+        }
+
+        let overlap = self
+            .segments
+            .iter()
+            .filter(|other| {
+                // An empty segment has nothing to overlap with
+                !segment.bytes.is_empty()
+                    && !other.bytes.is_empty()
+                    && usize::from(segment.address) < other.end()
+                    && usize::from(other.address) < segment.end()
+            })
+            .map(|other| segment.address.max(other.address))
+            .min();
+        if let Some(address) = overlap {
+            return Err(Error::OverlappingSegments { address });
+        }
+
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Start running at `entry` rather than wherever the pc already is
+    pub fn set_entry(&mut self, entry: u16) {
+        self.entry = Some(entry);
+    }
+
+    pub fn entry(&self) -> Option<u16> {
+        self.entry
+    }
+
+    /// The segments, in the order they were added
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
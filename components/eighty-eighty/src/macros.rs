@@ -0,0 +1,174 @@
+//! The textual side of macro assembly: collecting blocks and substituting
+//! macro arguments. Expanding them is up to the assembler, since IF and
+//! REPT need the values of symbols
+
+use crate::assembler::{SourceLine, SourceLocation};
+
+/// A macro defined with MACRO and ENDM
+#[derive(Debug, Clone)]
+pub(crate) struct Macro {
+    pub(crate) parameters: Vec<String>,
+    pub(crate) body: Vec<SourceLine>,
+}
+
+/// The first word of the code of `text`, and the word after it, upper case
+pub(crate) fn words(text: &str) -> (String, String) {
+    let code = text.split(';').next().unwrap_or_default();
+    let mut words = code
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_uppercase);
+    (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    )
+}
+
+/// Whether the line opens a block that ENDM closes
+fn opens_block(text: &str) -> bool {
+    let (first, second) = words(text);
+    first == "REPT" || ["MACRO", "REPT"].contains(&second.as_str())
+}
+
+/// The lines of the block starting at `lines[start]`, up to its ENDM, and
+/// the index of the line after the ENDM. `None` if there's no ENDM
+pub(crate) fn collect_block(
+    lines: &[SourceLine],
+    start: usize,
+) -> Option<(Vec<SourceLine>, usize)> {
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        if opens_block(&line.text) {
+            depth += 1;
+        } else if words(&line.text).0 == "ENDM" {
+            if depth == 0 {
+                return Some((lines[start..index].to_vec(), index + 1));
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+/// Split macro arguments on commas outside of strings and `<>` brackets,
+/// removing the brackets
+pub(crate) fn split_arguments(arguments: &str) -> Vec<String> {
+    if arguments.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut depth = 0;
+    for c in arguments.chars() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '<' => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            None if c == '>' && depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    continue;
+                }
+            }
+            None if c == ',' && depth == 0 => {
+                split.push(current.trim().to_owned());
+                current.clear();
+                continue;
+            }
+            None => {}
+        }
+        current.push(c);
+    }
+    split.push(current.trim().to_owned());
+    split
+}
+
+/// Replace the names in `text` that are keys of `values`, which are upper
+/// case
+///
+/// Names in strings are only replaced when next to an `&`, and any `&` next
+/// to a replaced name is removed, so `'&NAME'` and `LABEL&N` work as they do
+/// in Intel macros.
+pub(crate) fn substitute(text: &str, values: &[(String, String)]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let is_name_start = |c: char| c.is_ascii_alphabetic() || "_?@.".contains(c);
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || "_?@.$".contains(c);
+
+    let mut output = String::new();
+    let mut quote = None;
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        if quote.is_none() && c == ';' {
+            output.extend(&chars[index..]);
+            break;
+        }
+        // The letters in a number like 0FFH aren't a name
+        if c.is_ascii_digit() {
+            while index < chars.len() && is_name_char(chars[index]) {
+                output.push(chars[index]);
+                index += 1;
+            }
+            continue;
+        }
+        if !is_name_start(c) {
+            match quote {
+                Some(open) if c == open => quote = None,
+                None if c == '\'' || c == '"' => quote = Some(c),
+                _ => {}
+            }
+            output.push(c);
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        while index < chars.len() && is_name_char(chars[index]) {
+            index += 1;
+        }
+        let name: String = chars[start..index].iter().collect();
+        let after_ampersand = start > 0 && chars[start - 1] == '&';
+        let before_ampersand = chars.get(index) == Some(&'&');
+        let value = values
+            .iter()
+            .find(|(parameter, _)| name.eq_ignore_ascii_case(parameter))
+            .map(|(_, value)| value)
+            .filter(|_| quote.is_none() || after_ampersand || before_ampersand);
+
+        match value {
+            Some(value) => {
+                if after_ampersand {
+                    output.pop();
+                }
+                output.push_str(value);
+                if before_ampersand {
+                    index += 1;
+                }
+            }
+            None => output.push_str(&name),
+        }
+    }
+    output
+}
+
+/// `text` split into lines, each knowing where it came from
+pub(crate) fn source_lines(file: &str, text: &str) -> Vec<SourceLine> {
+    text.lines()
+        .enumerate()
+        .map(|(index, text)| SourceLine {
+            text: text.to_owned(),
+            location: SourceLocation {
+                file: file.to_owned(),
+                line: index + 1,
+            },
+        })
+        .collect()
+}
@@ -0,0 +1,358 @@
+use std::fmt;
+use std::ops::{Index, IndexMut, RangeInclusive};
+use std::sync::Arc;
+
+use crate::Error;
+
+pub(crate) const MEMORY_SIZE: usize = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A data read or write made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub kind: AccessKind,
+    /// The value read, or written
+    pub value: u8,
+}
+
+/// Everything the CPU can address: RAM, ROM and memory mapped devices
+///
+/// [`Memory`] is the default implementation, a flat 64 KiB of RAM. Use
+/// [`MappedMemory`] to describe a board with ROM, mirrored ranges or devices.
+pub trait MemoryBus {
+    /// Read the byte at `address` as the CPU does. Memory mapped devices may
+    /// react to being read
+    fn read(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    /// Read the byte at `address` without any side effects, for debuggers
+    /// and disassemblers
+    fn peek(&self, address: u16) -> u8;
+
+    /// Write a byte as the CPU does. Buses can refuse writes, e.g. to ROM, by
+    /// returning an error, which aborts the instruction
+    fn write(&mut self, address: u16, value: u8) -> Result<(), Error>;
+
+    /// Write a byte bypassing any protection, for loaders and debuggers.
+    /// Writes to ROM succeed
+    fn poke(&mut self, address: u16, value: u8);
+
+    /// Whether `address` is RAM or ROM of its own, rather than a device, a
+    /// mirror or unmapped. Save states only capture and restore these
+    fn is_storage(&self, _address: u16) -> bool {
+        true
+    }
+}
+
+impl MemoryBus for [u8; MEMORY_SIZE] {
+    fn peek(&self, address: u16) -> u8 {
+        self[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        self[address as usize] = value;
+        Ok(())
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        self[address as usize] = value;
+    }
+}
+
+const PAGE_SIZE: usize = 256;
+const PAGES_PER_BLOCK: usize = 16;
+const BLOCKS: usize = MEMORY_SIZE / (PAGE_SIZE * PAGES_PER_BLOCK);
+
+type Page = [u8; PAGE_SIZE];
+type Block = [Arc<Page>; PAGES_PER_BLOCK];
+
+/// 64 KiB of RAM, stored on the heap as copy-on-write pages
+///
+/// Cloning only copies a handful of pointers. Pages are shared between
+/// clones until one of them writes to it, so keeping a snapshot per step
+/// costs memory in proportion to the pages each step touches.
+#[derive(Clone, PartialEq)]
+pub struct Memory {
+    blocks: [Arc<Block>; BLOCKS],
+}
+
+impl Memory {
+    /// Zeroed memory. Every page starts out shared
+    pub fn new() -> Self {
+        let page = Arc::new([0; PAGE_SIZE]);
+        let block = Arc::new(std::array::from_fn(|_| page.clone()));
+        Self {
+            blocks: std::array::from_fn(|_| block.clone()),
+        }
+    }
+
+    fn page(&self, address: usize) -> &Page {
+        &self.blocks[address / (PAGE_SIZE * PAGES_PER_BLOCK)][address / PAGE_SIZE % PAGES_PER_BLOCK]
+    }
+
+    fn page_mut(&mut self, address: usize) -> &mut Page {
+        let block = Arc::make_mut(&mut self.blocks[address / (PAGE_SIZE * PAGES_PER_BLOCK)]);
+        Arc::make_mut(&mut block[address / PAGE_SIZE % PAGES_PER_BLOCK])
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = u8;
+
+    fn index(&self, address: usize) -> &u8 {
+        &self.page(address)[address % PAGE_SIZE]
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, address: usize) -> &mut u8 {
+        &mut self.page_mut(address)[address % PAGE_SIZE]
+    }
+}
+
+impl MemoryBus for Memory {
+    fn peek(&self, address: u16) -> u8 {
+        self[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        self[address as usize] = value;
+        Ok(())
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        self[address as usize] = value;
+    }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the pages that have been written to are worth showing
+        let zero_page = [0; PAGE_SIZE];
+        let mut map = f.debug_map();
+        for (index, block) in self.blocks.iter().enumerate() {
+            for (offset, page) in block.iter().enumerate() {
+                if **page != zero_page {
+                    let address = (index * PAGES_PER_BLOCK + offset) * PAGE_SIZE;
+                    map.entry(&format_args!("{:#06x}", address), &&page[..]);
+                }
+            }
+        }
+        map.finish()
+    }
+}
+
+/// A memory mapped peripheral. Addresses are given as offsets from the start
+/// of the region the device is mapped at
+pub trait Device {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    /// Read without side effects, for debuggers and disassemblers
+    fn peek(&self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, value: u8);
+}
+
+enum RegionKind {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    // Accesses are redirected into `target`, repeating if it is shorter
+    Mirror(RangeInclusive<u16>),
+    Device(Box<dyn Device>),
+}
+
+struct Region {
+    range: RangeInclusive<u16>,
+    kind: RegionKind,
+}
+
+/// The furthest a mirror may redirect to another mirror
+const MAX_MIRROR_DEPTH: usize = 8;
+
+/// A memory bus assembled from regions of RAM, ROM, mirrors and devices
+///
+/// Regions added later take precedence where they overlap. Unmapped
+/// addresses read as 0xff and ignore writes, as do writes to ROM unless
+/// `report_rom_writes` is set.
+///
+/// ```
+/// use eighty_eighty::MappedMemory;
+///
+/// let memory = MappedMemory::new()
+///     .rom(0x0000, vec![0x76])
+///     .ram(0x2000..=0x23ff)
+///     .mirror(0x2400..=0x3fff, 0x2000..=0x23ff);
+/// ```
+#[derive(Default)]
+pub struct MappedMemory {
+    regions: Vec<Region>,
+    report_rom_writes: bool,
+}
+
+impl MappedMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map zeroed RAM over `range`
+    pub fn ram(self, range: RangeInclusive<u16>) -> Self {
+        let len = range.len();
+        self.with_region(range, RegionKind::Ram(vec![0; len]))
+    }
+
+    /// Map `data` as read only memory starting at `start`. Data past 0xffff
+    /// is dropped, and empty data maps nothing
+    pub fn rom(self, start: u16, mut data: Vec<u8>) -> Self {
+        if data.is_empty() {
+            return self;
+        }
+        let end = (usize::from(start) + data.len() - 1).min(0xffff);
+        data.truncate(end - usize::from(start) + 1);
+        self.with_region(start..=end as u16, RegionKind::Rom(data))
+    }
+
+    /// Make `range` an alias of `target`. If `target` is shorter than
+    /// `range` it repeats to fill it. An empty `target` maps nothing
+    pub fn mirror(self, range: RangeInclusive<u16>, target: RangeInclusive<u16>) -> Self {
+        if target.is_empty() {
+            return self;
+        }
+        self.with_region(range, RegionKind::Mirror(target))
+    }
+
+    /// Map a device over `range`
+    pub fn device(self, range: RangeInclusive<u16>, device: impl Device + 'static) -> Self {
+        self.with_region(range, RegionKind::Device(Box::new(device)))
+    }
+
+    /// Whether writes to ROM fail with `Error::WriteToRom` rather than being
+    /// ignored
+    pub fn report_rom_writes(mut self, report: bool) -> Self {
+        self.report_rom_writes = report;
+        self
+    }
+
+    fn with_region(mut self, range: RangeInclusive<u16>, kind: RegionKind) -> Self {
+        self.regions.push(Region { range, kind });
+        self
+    }
+
+    /// The index of the region `address` ends up in, following mirrors, and
+    /// the offset into it
+    fn resolve(&self, mut address: u16) -> Option<(usize, u16)> {
+        for _ in 0..MAX_MIRROR_DEPTH {
+            let (index, region) = self
+                .regions
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, region)| region.range.contains(&address))?;
+            let offset = address - region.range.start();
+
+            match &region.kind {
+                RegionKind::Mirror(target) => {
+                    address = target.start() + (offset as usize % target.len()) as u16;
+                }
+                _ => return Some((index, offset)),
+            }
+        }
+        None
+    }
+}
+
+impl MemoryBus for MappedMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        match self.resolve(address) {
+            Some((index, offset)) => match &mut self.regions[index].kind {
+                RegionKind::Device(device) => device.read(offset),
+                _ => self.peek(address),
+            },
+            None => 0xff,
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match self.resolve(address) {
+            Some((index, offset)) => match &self.regions[index].kind {
+                RegionKind::Ram(data) | RegionKind::Rom(data) => data[offset as usize],
+                RegionKind::Device(device) => device.peek(offset),
+                RegionKind::Mirror(_) => unreachable!("mirrors are resolved"),
+            },
+            None => 0xff,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        let report_rom_writes = self.report_rom_writes;
+        if let Some((index, offset)) = self.resolve(address) {
+            match &mut self.regions[index].kind {
+                RegionKind::Ram(data) => data[offset as usize] = value,
+                RegionKind::Rom(_) if report_rom_writes => return Err(Error::WriteToRom(address)),
+                RegionKind::Rom(_) => (),
+                RegionKind::Device(device) => device.write(offset, value),
+                RegionKind::Mirror(_) => unreachable!("mirrors are resolved"),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_storage(&self, address: u16) -> bool {
+        let region = self
+            .regions
+            .iter()
+            .rev()
+            .find(|region| region.range.contains(&address));
+        matches!(
+            region.map(|region| &region.kind),
+            Some(RegionKind::Ram(_) | RegionKind::Rom(_))
+        )
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        if let Some((index, offset)) = self.resolve(address) {
+            match &mut self.regions[index].kind {
+                RegionKind::Ram(data) | RegionKind::Rom(data) => data[offset as usize] = value,
+                RegionKind::Device(device) => device.write(offset, value),
+                RegionKind::Mirror(_) => unreachable!("mirrors are resolved"),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MappedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        for region in &self.regions {
+            let kind = match &region.kind {
+                RegionKind::Ram(_) => "RAM".to_owned(),
+                RegionKind::Rom(_) => "ROM".to_owned(),
+                RegionKind::Mirror(target) => {
+                    format!("mirror of {:#06x}..={:#06x}", target.start(), target.end())
+                }
+                RegionKind::Device(_) => "device".to_owned(),
+            };
+            list.entry(&format_args!(
+                "{:#06x}..={:#06x}: {}",
+                region.range.start(),
+                region.range.end(),
+                kind
+            ));
+        }
+        list.finish()
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+
+use crate::Error;
+
+/// The version written by `SaveState::to_bytes`. Bump it when the meaning of
+/// an existing chunk changes; new chunks don't need a new version
+pub const SAVE_STATE_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 4] = b"8080";
+
+pub(crate) const ROW_SIZE: usize = 16;
+
+/// The condition codes, spelled out
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Flags {
+    pub zero: bool,
+    pub sign: bool,
+    pub parity: bool,
+    pub carry: bool,
+    pub auxiliary_carry: bool,
+}
+
+/// A snapshot of a running machine, taken with `Cpu::snapshot` and restored
+/// with `Cpu::restore`
+///
+/// The binary form (`to_bytes`/`from_bytes`) is what `Cpu::save_state` and
+/// `Cpu::load_state` use. With the `serde` feature enabled it can also be
+/// serialized to a human readable format, with memory as rows of hex.
+///
+/// Pending interrupt requests come from devices outside the CPU and aren't
+/// saved.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SaveState {
+    pub version: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub pc: u16,
+    pub sp: u16,
+    pub flags: Flags,
+    pub interrupts_enabled: bool,
+    pub interrupt_delay: bool,
+    pub halted: bool,
+    pub bus: u8,
+    pub cycles: u64,
+    /// The non-zero 16 byte rows of memory, by their address, which is a
+    /// multiple of 16
+    #[cfg_attr(feature = "serde", serde(with = "hex_rows"))]
+    pub memory: BTreeMap<u16, [u8; ROW_SIZE]>,
+}
+
+impl SaveState {
+    /// Encode as a header followed by tagged, length prefixed chunks
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(SAVE_STATE_VERSION.to_le_bytes());
+
+        let mut registers = vec![self.a, self.b, self.c, self.d, self.e, self.h, self.l];
+        registers.extend(self.pc.to_le_bytes());
+        registers.extend(self.sp.to_le_bytes());
+        write_chunk(&mut bytes, b"REGS", &registers);
+
+        let flags = [
+            self.flags.zero,
+            self.flags.sign,
+            self.flags.parity,
+            self.flags.carry,
+            self.flags.auxiliary_carry,
+        ];
+        write_chunk(&mut bytes, b"FLAG", &flags.map(u8::from));
+
+        let interrupts = [
+            self.interrupts_enabled.into(),
+            self.interrupt_delay.into(),
+            self.halted.into(),
+            self.bus,
+        ];
+        write_chunk(&mut bytes, b"INTR", &interrupts);
+
+        write_chunk(&mut bytes, b"CYCL", &self.cycles.to_le_bytes());
+
+        let mut memory = Vec::with_capacity(self.memory.len() * (2 + ROW_SIZE));
+        for (address, row) in &self.memory {
+            memory.extend(address.to_le_bytes());
+            memory.extend(row);
+        }
+        write_chunk(&mut bytes, b"MEM ", &memory);
+
+        bytes
+    }
+
+    /// Decode the output of `to_bytes` from this or an older version.
+    /// Unknown chunks are skipped and missing ones are left at their defaults
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(Error::InvalidSaveState("not a save state"));
+        }
+        let version = reader.u16()?;
+        if version > SAVE_STATE_VERSION {
+            return Err(Error::InvalidSaveState("saved by a newer version"));
+        }
+
+        let mut state = SaveState {
+            version,
+            ..SaveState::default()
+        };
+
+        while !reader.0.is_empty() {
+            let tag = reader.take(4)?;
+            let len = u32::from_le_bytes(reader.array()?) as usize;
+            // Chunks may grow in later versions, so only the start of each
+            // is read
+            let mut chunk = Reader(reader.take(len)?);
+
+            match tag {
+                b"REGS" => {
+                    let [a, b, c, d, e, h, l] = chunk.array()?;
+                    (state.a, state.b, state.c, state.d) = (a, b, c, d);
+                    (state.e, state.h, state.l) = (e, h, l);
+                    state.pc = chunk.u16()?;
+                    state.sp = chunk.u16()?;
+                }
+                b"FLAG" => {
+                    let [zero, sign, parity, carry, auxiliary_carry] = chunk.array()?;
+                    state.flags = Flags {
+                        zero: zero != 0,
+                        sign: sign != 0,
+                        parity: parity != 0,
+                        carry: carry != 0,
+                        auxiliary_carry: auxiliary_carry != 0,
+                    };
+                }
+                b"INTR" => {
+                    let [interrupts_enabled, interrupt_delay, halted, bus] = chunk.array()?;
+                    state.interrupts_enabled = interrupts_enabled != 0;
+                    state.interrupt_delay = interrupt_delay != 0;
+                    state.halted = halted != 0;
+                    state.bus = bus;
+                }
+                b"CYCL" => state.cycles = u64::from_le_bytes(chunk.array()?),
+                b"MEM " => {
+                    while !chunk.0.is_empty() {
+                        let address = chunk.u16()?;
+                        if usize::from(address) % ROW_SIZE != 0 {
+                            return Err(Error::InvalidSaveState("unaligned memory row"));
+                        }
+                        state.memory.insert(address, chunk.array()?);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    bytes.extend(tag);
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(payload);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::InvalidSaveState("unexpected end of data"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+}
+
+/// Memory as a map of `"0x1230"` to `"3e 42 ..."`, one line per row
+#[cfg(feature = "serde")]
+mod hex_rows {
+    use std::collections::BTreeMap;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::ROW_SIZE;
+
+    pub(super) fn serialize<S: Serializer>(
+        memory: &BTreeMap<u16, [u8; ROW_SIZE]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(memory.iter().map(|(address, row)| {
+            let row = row
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            (format!("{:#06x}", address), row)
+        }))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<u16, [u8; ROW_SIZE]>, D::Error> {
+        let rows = BTreeMap::<String, String>::deserialize(deserializer)?;
+        rows.into_iter()
+            .map(|(address, row)| {
+                let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+                    .map_err(|_| D::Error::custom(format!("bad address {}", address)))?;
+                if usize::from(address) % ROW_SIZE != 0 {
+                    return Err(D::Error::custom(crate::Error::InvalidSaveState(
+                        "unaligned memory row",
+                    )));
+                }
+                let bytes = row
+                    .split_whitespace()
+                    .map(|byte| u8::from_str_radix(byte, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| D::Error::custom(format!("bad row at {:#06x}", address)))?;
+                let row = bytes.try_into().map_err(|_| {
+                    D::Error::custom(format!("row at {:#06x} isn't 16 bytes", address))
+                })?;
+                Ok((address, row))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+
+use crate::{Analysis, Error, Instruction, InstructionClass};
+
+/// Names for addresses, so that disassembly can show `CALL PrintString`
+/// rather than `CALL 0x1a2b`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SymbolTable {
+    names: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a symbol file with one symbol per line, as either `NAME VALUE`
+    /// or `VALUE NAME`
+    ///
+    /// Every line has the same order, taken from the first line where only
+    /// one order makes sense, so `BEEF ABC` means the same as `0BEEF ABC` in
+    /// a file of `VALUE NAME` lines. The name may be followed by a colon,
+    /// and `EQU` or `=` may come between the two. Values are hex, with an
+    /// optional `0x` or `$` prefix or `H` suffix. Anything after a `;` is a
+    /// comment. This covers the symbol listings of most 8080 assemblers.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut lines = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let tokens: Vec<_> = line
+                .split_whitespace()
+                .filter(|token| !token.eq_ignore_ascii_case("EQU") && *token != "=")
+                .map(|token| token.trim_end_matches(':'))
+                .collect();
+
+            match tokens[..] {
+                [] => continue,
+                [first, second] => lines.push((index + 1, first, second)),
+                _ => return Err(Error::InvalidSymbolFile { line: index + 1 }),
+            }
+        }
+
+        let name_first =
+            |first: &str, second: &str| is_name(first) && parse_address(second).is_some();
+        // Name first unless a line can only be read the other way round
+        let value_first = lines
+            .iter()
+            .map(|&(_, first, second)| (name_first(first, second), name_first(second, first)))
+            .find(|(forward, backward)| forward != backward)
+            .is_some_and(|(_, backward)| backward);
+
+        let mut symbols = Self::new();
+        for (line, first, second) in lines {
+            let (name, value) = if value_first {
+                (second, first)
+            } else {
+                (first, second)
+            };
+            match parse_address(value) {
+                Some(address) if is_name(name) => symbols.insert(address, name),
+                _ => return Err(Error::InvalidSymbolFile { line }),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Name `address`, replacing any name it already had
+    pub fn insert(&mut self, address: u16, name: impl Into<String>) {
+        self.names.insert(address, name.into());
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    /// The address with the name `name`
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.iter()
+            .find(|&(_, other)| other == name)
+            .map(|(address, _)| address)
+    }
+
+    /// Every symbol, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names
+            .iter()
+            .map(|(&address, name)| (address, name.as_str()))
+    }
+
+    /// Name the targets of the branches found by `analysis` that don't have
+    /// a name yet, as `SUB_1A2B` for calls and RSTs and `L_1A2B` for jumps
+    pub fn add_generated_labels(&mut self, analysis: &Analysis) {
+        let mut labels = BTreeMap::new();
+        for (_, instruction) in analysis.instructions() {
+            let Some(target) = instruction.branch_target() else {
+                continue;
+            };
+            if analysis.instruction_at(target).is_none() {
+                continue;
+            }
+
+            let subroutine = InstructionClass::Call.matches(&instruction)
+                || InstructionClass::Rst.matches(&instruction);
+            let label = labels.entry(target).or_insert(false);
+            *label |= subroutine;
+        }
+
+        for (address, subroutine) in labels {
+            let prefix = if subroutine { "SUB" } else { "L" };
+            self.names
+                .entry(address)
+                .or_insert_with(|| format!("{}_{:04X}", prefix, address));
+        }
+    }
+
+    /// How to show `address`: its name, or hex if it doesn't have one
+    pub fn describe(&self, address: u16) -> String {
+        match self.name(address) {
+            Some(name) => name.to_owned(),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    /// `instruction` with its address operand shown by name, where it has
+    /// one
+    pub fn format_instruction(&self, instruction: &Instruction) -> String {
+        let text = instruction.to_string();
+        match instruction.address_operand() {
+            Some(address) => match self.name(address) {
+                Some(name) => text.replacen(&format!("{:#06x}", address), name, 1),
+                None => text,
+            },
+            None => text,
+        }
+    }
+}
+
+fn parse_address(token: &str) -> Option<u16> {
+    let digits = token
+        .strip_prefix("0x")
+        .or_else(|| token.strip_prefix("0X"))
+        .or_else(|| token.strip_prefix('$'))
+        .or_else(|| token.strip_suffix('H').or_else(|| token.strip_suffix('h')))
+        .unwrap_or(token);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn is_name(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || "_.?@".contains(c))
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.?@$".contains(c))
+}
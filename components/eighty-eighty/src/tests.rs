@@ -1,6 +1,11 @@
 use crate::cpu::{self, Cpu};
 use crate::instruction::Reg;
-use crate::Instruction;
+use crate::{
+    analyze, assemble, assemble_file, disassemble, AccessKind, BinaryTraceWriter, Debugger, Device,
+    DisassembledLine, GdbServer, Image, Instruction, InstructionClass, IntelHex, IntelHexError, Io,
+    MappedMemory, MemoryAccess, MemoryBus, PortDirection, Ports, SaveState, StopReason,
+    SymbolTable, TextTraceWriter, TraceRecord, WatchKind,
+};
 use Instruction::*;
 
 #[test]
@@ -24,7 +29,7 @@ fn parity() {
 
 #[test]
 fn processor_status_word() {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     assert_eq!(cpu.processor_status_word(), 0b00000010);
 
@@ -48,7 +53,7 @@ fn processor_status_word() {
 
 #[test]
 fn mvi_and_add() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     assert_eq!(cpu.a, 0);
 
@@ -69,7 +74,7 @@ fn mvi_and_add() -> Result<(), cpu::Error> {
 // [LXI] - Load Register Pair Immediate
 #[test]
 fn lxi() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -103,7 +108,7 @@ fn lxi() -> Result<(), cpu::Error> {
 // [STAX] - Store Accumulator Indirect
 #[test]
 fn stax() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.a = 0x34;
 
@@ -134,7 +139,7 @@ fn stax() -> Result<(), cpu::Error> {
 // [INX] - Increment Register Pair
 #[test]
 fn inx() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.condition_codes.p = 12;
     cpu.condition_codes.z = 13;
@@ -181,7 +186,7 @@ fn inx() -> Result<(), cpu::Error> {
 // [INR] - Increment Register
 #[test]
 fn inr_register() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(INR { register: Reg::A })?;
 
@@ -207,7 +212,7 @@ fn inr_register() -> Result<(), cpu::Error> {
 // [INR] - Increment Memory
 #[test]
 fn inr_memory() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(INR { register: Reg::M })?;
 
@@ -231,7 +236,7 @@ fn inr_memory() -> Result<(), cpu::Error> {
 // [DCR] - Decrement Register
 #[test]
 fn dcr_register() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(DCR { register: Reg::B })?;
 
@@ -253,7 +258,7 @@ fn dcr_register() -> Result<(), cpu::Error> {
 // [DCR] - Decrement Memory
 #[test]
 fn dcr_memory() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.load_into_memory(vec![0x35])?;
 
@@ -271,7 +276,7 @@ fn dcr_memory() -> Result<(), cpu::Error> {
 // [MVI] - Move Immediate Register
 #[test]
 fn mvi_reg() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::B,
@@ -286,7 +291,7 @@ fn mvi_reg() -> Result<(), cpu::Error> {
 // [MVI] - Move Immediate Memory
 #[test]
 fn mvi_mem() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::H,
@@ -303,10 +308,32 @@ fn mvi_mem() -> Result<(), cpu::Error> {
     Ok(())
 }
 
+// [MOV] - Opcodes 0x54, 0x59 and 0x61 move between the registers they encode
+#[test]
+fn mov_decoded_registers() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // MOV D, H; MOV E, C; MOV H, C
+    cpu.load_into_memory(vec![0x54, 0x59, 0x61])?;
+    cpu.c = 0x12;
+    cpu.h = 0x34;
+    cpu.l = 0x56;
+
+    cpu.step()?;
+    assert_eq!(cpu.d, 0x34);
+    cpu.step()?;
+    assert_eq!(cpu.e, 0x12);
+    cpu.step()?;
+    assert_eq!(cpu.h, 0x12);
+    assert_eq!(cpu.c, 0x12);
+
+    Ok(())
+}
+
 // [RLC] - Rotate Left
 #[test]
 fn rlc() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::A,
@@ -340,7 +367,7 @@ fn rlc() -> Result<(), cpu::Error> {
 // [DAD] - Add Register Pair to H and L
 #[test]
 fn dad() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -355,7 +382,7 @@ fn dad() -> Result<(), cpu::Error> {
     assert_eq!(cpu.h, 0x55);
     assert_eq!(cpu.l, 0x55);
 
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -372,7 +399,7 @@ fn dad() -> Result<(), cpu::Error> {
     assert_eq!(cpu.l, 0x34);
     assert_eq!(cpu.condition_codes.cy, 0);
 
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -401,7 +428,7 @@ fn dad() -> Result<(), cpu::Error> {
 // [LDAX] - Load Accumulator Indirect
 #[test]
 fn ldax() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -420,7 +447,7 @@ fn ldax() -> Result<(), cpu::Error> {
 // [DCX] - Decrement Register Pair
 #[test]
 fn dcx() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -452,7 +479,7 @@ fn dcx() -> Result<(), cpu::Error> {
 // [PUSH] - Push
 #[test]
 fn push() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -474,7 +501,7 @@ fn push() -> Result<(), cpu::Error> {
 // [XCHG] - Exchange H and L with D and E
 #[test]
 fn xchg() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::H,
@@ -496,10 +523,122 @@ fn xchg() -> Result<(), cpu::Error> {
     Ok(())
 }
 
+// [SHLD] - Store H and L Direct
+#[test]
+fn shld() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0xae29,
+    })?;
+    cpu.execute_instruction(SHLD { address: 0x010a })?;
+
+    assert_eq!(cpu.memory[0x010a], 0x29);
+    assert_eq!(cpu.memory[0x010b], 0xae);
+
+    // The high byte wraps around to the bottom of memory
+    cpu.execute_instruction(SHLD { address: 0xffff })?;
+
+    assert_eq!(cpu.memory[0xffff], 0x29);
+    assert_eq!(cpu.memory[0x0000], 0xae);
+
+    Ok(())
+}
+
+// [LHLD] - Load H and L Direct
+#[test]
+fn lhld() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.memory[0x025b] = 0xff;
+    cpu.memory[0x025c] = 0x03;
+    cpu.execute_instruction(LHLD { address: 0x025b })?;
+
+    assert_eq!(cpu.h, 0x03);
+    assert_eq!(cpu.l, 0xff);
+
+    cpu.memory[0xffff] = 0x34;
+    cpu.memory[0x0000] = 0x12;
+    cpu.execute_instruction(LHLD { address: 0xffff })?;
+
+    assert_eq!(cpu.h, 0x12);
+    assert_eq!(cpu.l, 0x34);
+
+    Ok(())
+}
+
+// [XTHL] - Exchange Stack Top with H and L
+#[test]
+fn xthl() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.sp = 0x10ad;
+    cpu.memory[0x10ad] = 0xf0;
+    cpu.memory[0x10ae] = 0x0d;
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x0b3c,
+    })?;
+    cpu.execute_instruction(XTHL)?;
+
+    assert_eq!(cpu.h, 0x0d);
+    assert_eq!(cpu.l, 0xf0);
+    assert_eq!(cpu.memory[0x10ad], 0x3c);
+    assert_eq!(cpu.memory[0x10ae], 0x0b);
+    assert_eq!(cpu.sp, 0x10ad);
+
+    // SP + 1 wraps around to the bottom of memory
+    cpu.sp = 0xffff;
+    cpu.memory[0xffff] = 0x78;
+    cpu.memory[0x0000] = 0x56;
+    cpu.execute_instruction(XTHL)?;
+
+    assert_eq!(cpu.h, 0x56);
+    assert_eq!(cpu.l, 0x78);
+    assert_eq!(cpu.memory[0xffff], 0xf0);
+    assert_eq!(cpu.memory[0x0000], 0x0d);
+
+    Ok(())
+}
+
+// [SPHL] - Move H and L to SP
+#[test]
+fn sphl() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x506c,
+    })?;
+    cpu.execute_instruction(SPHL)?;
+
+    assert_eq!(cpu.sp, 0x506c);
+    assert_eq!(cpu.h, 0x50);
+    assert_eq!(cpu.l, 0x6c);
+
+    Ok(())
+}
+
+// [PCHL] - Load Program Counter
+#[test]
+fn pchl() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // LXI H, 0x413e; PCHL
+    cpu.load_into_memory(vec![0x21, 0x3e, 0x41, 0xe9])?;
+    cpu.step()?;
+    cpu.step()?;
+
+    assert_eq!(cpu.pc, 0x413e);
+
+    Ok(())
+}
+
 // [POP] - Pop
 #[test]
 fn pop() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::B,
@@ -522,7 +661,7 @@ fn pop() -> Result<(), cpu::Error> {
 // [POP] - Pop PSW
 #[test]
 fn pop_psw() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(SBI { data: 1 })?;
 
@@ -556,13 +695,48 @@ fn pop_psw() -> Result<(), cpu::Error> {
 // [OUT] - Output
 #[test]
 fn out() -> Result<(), cpu::Error> {
-    let mut i = 0;
+    let mut written = (0, 0);
 
-    let mut cpu = Cpu::new(|v| i = v);
+    let mut cpu = Cpu::new(|port, value| written = (port, value));
 
+    cpu.a = 0x34;
     cpu.execute_instruction(OUT { data: 0x12 })?;
 
-    assert_eq!(i, 0x12);
+    assert_eq!(written, (0x12, 0x34));
+
+    Ok(())
+}
+
+// [IN] - Input
+#[test]
+fn input() -> Result<(), cpu::Error> {
+    let mut written = Vec::new();
+
+    let mut cpu = Cpu::new(Ports::new(
+        |port, _| port.wrapping_mul(2),
+        |port, value| written.push((port, value)),
+    ));
+
+    cpu.execute_instruction(IN { data: 0x21 })?;
+
+    assert_eq!(cpu.a, 0x42);
+
+    cpu.execute_instruction(OUT { data: 0x01 })?;
+
+    assert_eq!(written, vec![(0x01, 0x42)]);
+
+    Ok(())
+}
+
+// [IN] - Input from a port nothing drives reads the data bus
+#[test]
+fn input_unconnected() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.write_to_bus(0xff);
+    cpu.execute_instruction(IN { data: 0x01 })?;
+
+    assert_eq!(cpu.a, 0xff);
 
     Ok(())
 }
@@ -570,7 +744,7 @@ fn out() -> Result<(), cpu::Error> {
 // [ADD] - Add Register
 #[test]
 fn add_register() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::B,
@@ -597,7 +771,7 @@ fn add_register() -> Result<(), cpu::Error> {
 // [ADD] - Add Memory
 #[test]
 fn add_memory() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(LXI {
         register: Reg::H,
@@ -627,14 +801,14 @@ fn add_memory() -> Result<(), cpu::Error> {
 // [JZ] - Jump Zero
 #[test]
 fn jz() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(ADD { register: Reg::B })?;
 
     let jump_instruction = JZ { address: 0x12 };
     cpu.execute_instruction(jump_instruction)?;
 
-    assert_eq!(cpu.pc, 0x12 - jump_instruction.op_bytes() as u16);
+    assert_eq!(cpu.pc, 0x12);
 
     Ok(())
 }
@@ -642,7 +816,7 @@ fn jz() -> Result<(), cpu::Error> {
 // [JC] - Jump Carry
 #[test]
 fn jc() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::B,
@@ -656,7 +830,7 @@ fn jc() -> Result<(), cpu::Error> {
     let jump_instruction = JC { address: 0x82 };
     cpu.execute_instruction(jump_instruction)?;
 
-    assert_eq!(cpu.pc, 0x82 - jump_instruction.op_bytes() as u16);
+    assert_eq!(cpu.pc, 0x82);
 
     cpu.pc = 1;
     cpu.execute_instruction(ADD { register: Reg::B })?;
@@ -669,7 +843,7 @@ fn jc() -> Result<(), cpu::Error> {
 // [JNC] - Jump Not Carry
 #[test]
 fn jnc() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::B,
@@ -687,7 +861,7 @@ fn jnc() -> Result<(), cpu::Error> {
 
     cpu.execute_instruction(ADD { register: Reg::B })?;
     cpu.execute_instruction(jump_instruction)?;
-    assert_eq!(cpu.pc, 0x82 - jump_instruction.op_bytes() as u16);
+    assert_eq!(cpu.pc, 0x82);
 
     Ok(())
 }
@@ -695,7 +869,7 @@ fn jnc() -> Result<(), cpu::Error> {
 // [RRC] - Rotate Right
 #[test]
 fn rrc() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(MVI {
         register: Reg::A,
@@ -723,7 +897,7 @@ fn rrc() -> Result<(), cpu::Error> {
 // [RPE] - Return if Parity even
 #[test]
 fn rpe() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.condition_codes.p = 1;
 
@@ -745,13 +919,149 @@ fn rpe() -> Result<(), cpu::Error> {
 // Here, we are just testing interrupts really
 #[test]
 fn rst() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(EI)?;
+    cpu.generate_interrupt(2);
+
+    // EI only takes effect after the following instruction
+    cpu.step()?;
+    assert_eq!(cpu.pc, 1);
 
-    cpu.generate_interrupt(2)?;
+    cpu.step()?;
 
     assert_eq!(cpu.pc, 2 * 8);
 
     assert_eq!(cpu.sp, 0xFFFE);
+    assert_eq!(cpu.memory[0xfffe], 0x01);
+    assert_eq!(cpu.memory[0xffff], 0x00);
+
+    Ok(())
+}
+
+// [EI] - Enable Interrupts takes effect after the next instruction
+#[test]
+fn ei() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // EI; RET
+    cpu.load_into_memory(vec![0xfb, 0xc9])?;
+    cpu.sp = 0x100;
+    cpu.memory[0x100] = 0x00;
+    cpu.memory[0x101] = 0x20;
+    cpu.generate_interrupt(7);
+
+    assert!(!cpu.interrupts_enabled());
+
+    cpu.step()?;
+    assert!(cpu.interrupts_enabled());
+    assert_eq!(cpu.pc, 0x0001);
+
+    // The interrupt can't be taken before the RET that follows EI
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x2000);
+    assert_eq!(cpu.sp, 0x102);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0038);
+    assert_eq!(cpu.sp, 0x100);
+    assert_eq!(cpu.memory[0x100], 0x00);
+    assert_eq!(cpu.memory[0x101], 0x20);
+
+    // Acknowledging the interrupt disables further interrupts
+    assert!(!cpu.interrupts_enabled());
+
+    Ok(())
+}
+
+// [DI] - Disable Interrupts leaves requests pending
+#[test]
+fn di() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // EI; NOP; DI; NOP; NOP; EI; NOP
+    cpu.load_into_memory(vec![0xfb, 0x00, 0xf3, 0x00, 0x00, 0xfb, 0x00])?;
+
+    cpu.step()?;
+    cpu.step()?;
+    cpu.step()?;
+    assert!(!cpu.interrupts_enabled());
+
+    cpu.generate_interrupt(1);
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0005);
+    assert_eq!(cpu.pending_interrupts().count(), 1);
+
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0007);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0008);
+    assert_eq!(cpu.pending_interrupts().count(), 0);
+
+    Ok(())
+}
+
+// [HLT] - An interrupt resumes a halted CPU
+#[test]
+fn interrupt_resumes_hlt() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // EI; HLT
+    cpu.load_into_memory(vec![0xfb, 0x76])?;
+    cpu.sp = 0x100;
+
+    cpu.step()?;
+    cpu.step()?;
+    assert!(cpu.halted());
+
+    cpu.step()?;
+    assert!(cpu.halted());
+    assert_eq!(cpu.pc, 0x0002);
+
+    cpu.generate_interrupt(1);
+    cpu.step()?;
+
+    assert!(!cpu.halted());
+    assert_eq!(cpu.pc, 0x0008);
+    // Returning from the handler continues after the HLT
+    assert_eq!(cpu.memory[0xfe], 0x02);
+    assert_eq!(cpu.memory[0xff], 0x00);
+
+    Ok(())
+}
+
+// Any instruction can be placed on the data bus by an interrupting device
+#[test]
+fn interrupt_with_call() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.load_into_memory(vec![0xfb, 0x00, 0x00])?;
+    cpu.sp = 0x100;
+    cpu.request_interrupt(CALL { address: 0x1234 });
+    cpu.request_interrupt(MVI {
+        register: Reg::B,
+        value: 0x56,
+    });
+
+    cpu.step()?;
+    cpu.step()?;
+    cpu.step()?;
+
+    assert_eq!(cpu.pc, 0x1234);
+    assert_eq!(cpu.memory[0xfe], 0x02);
+    assert_eq!(cpu.memory[0xff], 0x00);
+
+    // The second request waits until interrupts are enabled again
+    assert_eq!(cpu.pending_interrupts().count(), 1);
+    cpu.execute_instruction(EI)?;
+    cpu.step()?;
+    assert_eq!(cpu.b, 0x00);
+    cpu.step()?;
+    assert_eq!(cpu.b, 0x56);
+    assert_eq!(cpu.pc, 0x1235);
 
     Ok(())
 }
@@ -759,7 +1069,7 @@ fn rst() -> Result<(), cpu::Error> {
 // [LDA] - Load Accumulator Direct
 #[test]
 fn lda() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.memory[0xab] = 23;
     cpu.execute_instruction(LDA { address: 0xab })?;
@@ -772,7 +1082,7 @@ fn lda() -> Result<(), cpu::Error> {
 // [STA] - Store Accumulator Direct
 #[test]
 fn sta() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.a = 0x1e;
     cpu.execute_instruction(STA { address: 0xf1 })?;
@@ -785,7 +1095,7 @@ fn sta() -> Result<(), cpu::Error> {
 // [ANI] - AND Immediate
 #[test]
 fn ani() -> Result<(), cpu::Error> {
-    let mut cpu = Cpu::new(|_| {});
+    let mut cpu = Cpu::new(|_, _| {});
 
     cpu.execute_instruction(ADI { data: 2 })?;
     cpu.execute_instruction(ANI { data: 4 })?;
@@ -798,3 +1108,2516 @@ fn ani() -> Result<(), cpu::Error> {
 
     Ok(())
 }
+
+// [ANA] - AND Register
+#[test]
+fn ana_register() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.condition_codes.cy = 1;
+    cpu.a = 0xfc;
+    cpu.b = 0x0f;
+    cpu.execute_instruction(ANA { register: Reg::B })?;
+
+    assert_eq!(cpu.a, 0x0c);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 0);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    Ok(())
+}
+
+// [ANA] - AND Memory
+#[test]
+fn ana_memory() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x2000,
+    })?;
+    cpu.memory[0x2000] = 0xf0;
+    cpu.a = 0x03;
+    cpu.condition_codes.cy = 1;
+    cpu.condition_codes.ac = 1;
+    cpu.execute_instruction(ANA { register: Reg::M })?;
+
+    assert_eq!(cpu.a, 0x00);
+    assert_eq!(cpu.memory[0x2000], 0xf0);
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.s, 0);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [XRA] - Exclusive OR Register
+#[test]
+fn xra_register() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // XRA A is the idiomatic way to zero the accumulator and clear the carry
+    cpu.a = 0x5c;
+    cpu.condition_codes.cy = 1;
+    cpu.condition_codes.ac = 1;
+    cpu.execute_instruction(XRA { register: Reg::A })?;
+
+    assert_eq!(cpu.a, 0x00);
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    cpu.a = 0xff;
+    cpu.b = 0x0f;
+    cpu.execute_instruction(XRA { register: Reg::B })?;
+
+    assert_eq!(cpu.a, 0xf0);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.p, 1);
+
+    Ok(())
+}
+
+// [XRA] - Exclusive OR Memory
+#[test]
+fn xra_memory() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x1234,
+    })?;
+    cpu.memory[0x1234] = 0x81;
+    cpu.a = 0x01;
+    cpu.execute_instruction(XRA { register: Reg::M })?;
+
+    assert_eq!(cpu.a, 0x80);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.p, 0);
+
+    Ok(())
+}
+
+// [ORA] - OR Register
+#[test]
+fn ora_register() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x33;
+    cpu.c = 0x0f;
+    cpu.condition_codes.cy = 1;
+    cpu.condition_codes.ac = 1;
+    cpu.execute_instruction(ORA { register: Reg::C })?;
+
+    assert_eq!(cpu.a, 0x3f);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 0);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [ORA] - OR Memory
+#[test]
+fn ora_memory() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x0100,
+    })?;
+    cpu.execute_instruction(ORA { register: Reg::M })?;
+
+    assert_eq!(cpu.a, 0x00);
+    assert_eq!(cpu.condition_codes.z, 1);
+
+    cpu.memory[0x0100] = 0x80;
+    cpu.execute_instruction(ORA { register: Reg::M })?;
+
+    assert_eq!(cpu.a, 0x80);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+
+    Ok(())
+}
+
+// [CMP] - Compare Register
+#[test]
+fn cmp_register() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x0a;
+    cpu.e = 0x05;
+    cpu.execute_instruction(CMP { register: Reg::E })?;
+
+    assert_eq!(cpu.a, 0x0a);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.a = 0x02;
+    cpu.execute_instruction(CMP { register: Reg::E })?;
+
+    assert_eq!(cpu.a, 0x02);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.cy, 1);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    cpu.a = 0x05;
+    cpu.execute_instruction(CMP { register: Reg::E })?;
+
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    Ok(())
+}
+
+// [CMP] - Compare Memory
+#[test]
+fn cmp_memory() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(LXI {
+        register: Reg::H,
+        value: 0x4000,
+    })?;
+    cpu.memory[0x4000] = 0x90;
+    cpu.a = 0x10;
+    cpu.execute_instruction(CMP { register: Reg::M })?;
+
+    assert_eq!(cpu.a, 0x10);
+    assert_eq!(cpu.memory[0x4000], 0x90);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    Ok(())
+}
+
+// [XRI] - Exclusive OR Immediate
+#[test]
+fn xri() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x3b;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(XRI { data: 0x81 })?;
+
+    assert_eq!(cpu.a, 0xba);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.p, 0);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [ORI] - OR Immediate
+#[test]
+fn ori() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0xb5;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(ORI { data: 0x0f })?;
+
+    assert_eq!(cpu.a, 0xbf);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.p, 0);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [CPI] - Compare Immediate
+#[test]
+fn cpi() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x4a;
+    cpu.execute_instruction(CPI { data: 0x40 })?;
+
+    assert_eq!(cpu.a, 0x4a);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    cpu.execute_instruction(CPI { data: 0x4a })?;
+
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    cpu.execute_instruction(CPI { data: 0x50 })?;
+
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    Ok(())
+}
+
+// [CALL] / [RET] - Call and Return
+#[test]
+fn call_and_ret() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    let mut program = vec![0; 0x11];
+    // LXI SP, 0x0100
+    program[0..3].copy_from_slice(&[0x31, 0x00, 0x01]);
+    // CALL 0x0010
+    program[3..6].copy_from_slice(&[0xcd, 0x10, 0x00]);
+    // HLT
+    program[6] = 0x76;
+    // RET
+    program[0x10] = 0xc9;
+    cpu.load_into_memory(program)?;
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0003);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0010);
+    assert_eq!(cpu.sp, 0x00fe);
+    assert_eq!(cpu.memory[0xff], 0x00);
+    assert_eq!(cpu.memory[0xfe], 0x06);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0006);
+    assert_eq!(cpu.sp, 0x0100);
+
+    cpu.step()?;
+    assert!(cpu.halted());
+
+    Ok(())
+}
+
+/// A CPU whose condition codes make exactly one of each pair of conditions hold
+fn cpu_with_flags(z: u8, cy: u8, p: u8, s: u8) -> Cpu<impl Io> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.condition_codes.z = z;
+    cpu.condition_codes.cy = cy;
+    cpu.condition_codes.p = p;
+    cpu.condition_codes.s = s;
+    cpu.pc = 0x1000;
+    cpu.sp = 0x2000;
+    cpu
+}
+
+// [JPO] [JPE] [JP] [JM] - Conditional Jumps
+#[test]
+fn conditional_jumps() -> Result<(), cpu::Error> {
+    let cases = [
+        (JPO { address: 0x42 }, (0, 0, 0, 0), true),
+        (JPO { address: 0x42 }, (0, 0, 1, 0), false),
+        (JPE { address: 0x42 }, (0, 0, 1, 0), true),
+        (JPE { address: 0x42 }, (0, 0, 0, 0), false),
+        (JP { address: 0x42 }, (0, 0, 0, 0), true),
+        (JP { address: 0x42 }, (0, 0, 0, 1), false),
+        (JM { address: 0x42 }, (0, 0, 0, 1), true),
+        (JM { address: 0x42 }, (0, 0, 0, 0), false),
+    ];
+
+    for (instruction, (z, cy, p, s), taken) in cases {
+        let mut cpu = cpu_with_flags(z, cy, p, s);
+        cpu.execute_instruction(instruction)?;
+
+        let expected_pc = if taken { 0x42 } else { 0x1000 };
+        assert_eq!(cpu.pc, expected_pc, "{}", instruction);
+        assert_eq!(cpu.sp, 0x2000, "{}", instruction);
+    }
+
+    Ok(())
+}
+
+// [CNZ] [CZ] [CNC] [CC] [CPO] [CPE] [CP] [CM] - Conditional Calls
+#[test]
+fn conditional_calls() -> Result<(), cpu::Error> {
+    let cases = [
+        (CNZ { address: 0x42 }, (0, 0, 0, 0), true),
+        (CNZ { address: 0x42 }, (1, 0, 0, 0), false),
+        (CZ { address: 0x42 }, (1, 0, 0, 0), true),
+        (CZ { address: 0x42 }, (0, 0, 0, 0), false),
+        (CNC { address: 0x42 }, (0, 0, 0, 0), true),
+        (CNC { address: 0x42 }, (0, 1, 0, 0), false),
+        (CC { address: 0x42 }, (0, 1, 0, 0), true),
+        (CC { address: 0x42 }, (0, 0, 0, 0), false),
+        (CPO { address: 0x42 }, (0, 0, 0, 0), true),
+        (CPO { address: 0x42 }, (0, 0, 1, 0), false),
+        (CPE { address: 0x42 }, (0, 0, 1, 0), true),
+        (CPE { address: 0x42 }, (0, 0, 0, 0), false),
+        (CP { address: 0x42 }, (0, 0, 0, 0), true),
+        (CP { address: 0x42 }, (0, 0, 0, 1), false),
+        (CM { address: 0x42 }, (0, 0, 0, 1), true),
+        (CM { address: 0x42 }, (0, 0, 0, 0), false),
+    ];
+
+    for (instruction, (z, cy, p, s), taken) in cases {
+        let mut cpu = cpu_with_flags(z, cy, p, s);
+        cpu.execute_instruction(instruction)?;
+
+        if taken {
+            assert_eq!(cpu.pc, 0x42, "{}", instruction);
+            assert_eq!(cpu.sp, 0x1ffe, "{}", instruction);
+            assert_eq!(cpu.memory[0x1fff], 0x10, "{}", instruction);
+            assert_eq!(cpu.memory[0x1ffe], 0x00, "{}", instruction);
+        } else {
+            assert_eq!(cpu.pc, 0x1000, "{}", instruction);
+            assert_eq!(cpu.sp, 0x2000, "{}", instruction);
+        }
+    }
+
+    Ok(())
+}
+
+// [RNZ] [RZ] [RNC] [RC] [RPO] [RPE] [RP] [RM] - Conditional Returns
+#[test]
+fn conditional_returns() -> Result<(), cpu::Error> {
+    let cases = [
+        (RNZ, (0, 0, 0, 0), true),
+        (RNZ, (1, 0, 0, 0), false),
+        (RZ, (1, 0, 0, 0), true),
+        (RZ, (0, 0, 0, 0), false),
+        (RNC, (0, 0, 0, 0), true),
+        (RNC, (0, 1, 0, 0), false),
+        (RC, (0, 1, 0, 0), true),
+        (RC, (0, 0, 0, 0), false),
+        (RPO, (0, 0, 0, 0), true),
+        (RPO, (0, 0, 1, 0), false),
+        (RPE, (0, 0, 1, 0), true),
+        (RPE, (0, 0, 0, 0), false),
+        (RP, (0, 0, 0, 0), true),
+        (RP, (0, 0, 0, 1), false),
+        (RM, (0, 0, 0, 1), true),
+        (RM, (0, 0, 0, 0), false),
+    ];
+
+    for (instruction, (z, cy, p, s), taken) in cases {
+        let mut cpu = cpu_with_flags(z, cy, p, s);
+        cpu.memory[0x2000] = 0x34;
+        cpu.memory[0x2001] = 0x12;
+        cpu.execute_instruction(instruction)?;
+
+        if taken {
+            assert_eq!(cpu.pc, 0x1234, "{}", instruction);
+            assert_eq!(cpu.sp, 0x2002, "{}", instruction);
+        } else {
+            assert_eq!(cpu.pc, 0x1000, "{}", instruction);
+            assert_eq!(cpu.sp, 0x2000, "{}", instruction);
+        }
+    }
+
+    Ok(())
+}
+
+// [ADD] - Auxiliary Carry
+#[test]
+fn add_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x6c;
+    cpu.d = 0x2e;
+    cpu.execute_instruction(ADD { register: Reg::D })?;
+
+    assert_eq!(cpu.a, 0x9a);
+    assert_eq!(cpu.condition_codes.z, 0);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.p, 1);
+    assert_eq!(cpu.condition_codes.s, 1);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.a = 0x21;
+    cpu.execute_instruction(ADI { data: 0x12 })?;
+
+    assert_eq!(cpu.a, 0x33);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [ADC] [ACI] - Auxiliary Carry includes the incoming carry
+#[test]
+fn adc_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x0f;
+    cpu.c = 0x00;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(ADC { register: Reg::C })?;
+
+    assert_eq!(cpu.a, 0x10);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.a = 0x56;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(ACI { data: 0xbe })?;
+
+    assert_eq!(cpu.a, 0x15);
+    assert_eq!(cpu.condition_codes.cy, 1);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    Ok(())
+}
+
+// [SUB] [SUI] - Auxiliary Carry is the carry of the complemented addition
+#[test]
+fn sub_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x3e;
+    cpu.execute_instruction(SUB { register: Reg::A })?;
+
+    assert_eq!(cpu.a, 0x00);
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.execute_instruction(SUI { data: 0x01 })?;
+
+    assert_eq!(cpu.a, 0xff);
+    assert_eq!(cpu.condition_codes.cy, 1);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [SBB] [SBI] - Auxiliary Carry with an incoming borrow
+#[test]
+fn sbb_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x04;
+    cpu.l = 0x02;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(SBB { register: Reg::L })?;
+
+    assert_eq!(cpu.a, 0x01);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.a = 0x10;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(SBI { data: 0x00 })?;
+
+    assert_eq!(cpu.a, 0x0f);
+    assert_eq!(cpu.condition_codes.cy, 0);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [INR] [DCR] - Auxiliary Carry
+#[test]
+fn inr_dcr_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.b = 0x0f;
+    cpu.execute_instruction(INR { register: Reg::B })?;
+
+    assert_eq!(cpu.b, 0x10);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.execute_instruction(INR { register: Reg::B })?;
+
+    assert_eq!(cpu.b, 0x11);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    cpu.execute_instruction(DCR { register: Reg::B })?;
+
+    assert_eq!(cpu.b, 0x10);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.execute_instruction(DCR { register: Reg::B })?;
+
+    assert_eq!(cpu.b, 0x0f);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [CPI] - Auxiliary Carry
+#[test]
+fn cpi_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x4a;
+    cpu.execute_instruction(CPI { data: 0x40 })?;
+
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    cpu.execute_instruction(CPI { data: 0x4b })?;
+
+    assert_eq!(cpu.condition_codes.cy, 1);
+    assert_eq!(cpu.condition_codes.ac, 0);
+
+    Ok(())
+}
+
+// [PUSH] - Push PSW carries the Auxiliary Carry in bit 4
+#[test]
+fn push_psw_auxiliary_carry() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.sp = 0x100;
+    cpu.a = 0x08;
+    cpu.execute_instruction(ADI { data: 0x08 })?;
+    cpu.execute_instruction(PUSH { register: Reg::Psw })?;
+
+    assert_eq!(cpu.memory[0xff], 0x10);
+    assert_eq!(cpu.memory[0xfe], 0b0001_0010);
+
+    Ok(())
+}
+
+// [RAL] - Rotate Left Through Carry
+#[test]
+fn ral() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0xb5;
+    cpu.execute_instruction(RAL)?;
+
+    assert_eq!(cpu.a, 0x6a);
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    cpu.execute_instruction(RAL)?;
+
+    assert_eq!(cpu.a, 0xd5);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    Ok(())
+}
+
+// [RAR] - Rotate Right Through Carry
+#[test]
+fn rar() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x6a;
+    cpu.condition_codes.cy = 1;
+    cpu.execute_instruction(RAR)?;
+
+    assert_eq!(cpu.a, 0xb5);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    cpu.execute_instruction(RAR)?;
+
+    assert_eq!(cpu.a, 0x5a);
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    Ok(())
+}
+
+// [CMA] - Complement Accumulator
+#[test]
+fn cma() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.a = 0x51;
+    cpu.execute_instruction(CMA)?;
+
+    assert_eq!(cpu.a, 0xae);
+    assert_eq!(cpu.processor_status_word(), 0b00000010);
+
+    Ok(())
+}
+
+// [STC] [CMC] - Set Carry and Complement Carry
+#[test]
+fn stc_and_cmc() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.execute_instruction(STC)?;
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    cpu.execute_instruction(STC)?;
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    cpu.execute_instruction(CMC)?;
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    cpu.execute_instruction(CMC)?;
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    Ok(())
+}
+
+// [DAA] - Decimal Adjust Accumulator
+#[test]
+fn daa() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // The example from the Intel 8080 manual
+    cpu.a = 0x9b;
+    cpu.execute_instruction(DAA)?;
+
+    assert_eq!(cpu.a, 0x01);
+    assert_eq!(cpu.condition_codes.cy, 1);
+    assert_eq!(cpu.condition_codes.ac, 1);
+
+    // 15 + 27 = 42 in BCD
+    cpu.a = 0x15;
+    cpu.condition_codes.cy = 0;
+    cpu.execute_instruction(ADI { data: 0x27 })?;
+    cpu.execute_instruction(DAA)?;
+
+    assert_eq!(cpu.a, 0x42);
+    assert_eq!(cpu.condition_codes.cy, 0);
+
+    // 99 + 1 = 100 in BCD, with the hundreds in the carry
+    cpu.a = 0x99;
+    cpu.execute_instruction(ADI { data: 0x01 })?;
+    cpu.execute_instruction(DAA)?;
+
+    assert_eq!(cpu.a, 0x00);
+    assert_eq!(cpu.condition_codes.z, 1);
+    assert_eq!(cpu.condition_codes.cy, 1);
+
+    Ok(())
+}
+
+/// DAA as described step by step in the Intel 8080 manual, returning the
+/// adjusted accumulator, CY and AC
+fn daa_reference(a: u8, cy: u8, ac: u8) -> (u8, u8, u8) {
+    let mut result = a as u16;
+    let mut ac_out = 0;
+    let mut cy_out = cy;
+
+    // 1. If the least significant four bits of the accumulator represents a
+    //    number greater than 9, or if the Auxiliary Carry bit is equal to
+    //    one, the accumulator is incremented by six
+    if result & 0x0f > 9 || ac == 1 {
+        ac_out = ((result & 0x0f) + 6 > 0x0f) as u8;
+        result += 0x06;
+    }
+
+    // 2. If the most significant four bits of the accumulator now represent a
+    //    number greater than 9, or if the normal carry bit is equal to one,
+    //    the most significant four bits of the accumulator are incremented by
+    //    six
+    if result >> 4 > 9 || cy == 1 {
+        result += 0x60;
+        if result > 0xff {
+            cy_out = 1;
+        }
+    }
+
+    (result as u8, cy_out, ac_out)
+}
+
+// [DAA] - Every accumulator value with every combination of CY and AC
+#[test]
+fn daa_exhaustive() -> Result<(), cpu::Error> {
+    let table = (0..=0xffu8)
+        .flat_map(|a| (0..=1).flat_map(move |cy| (0..=1).map(move |ac| (a, cy, ac))))
+        .map(|(a, cy, ac)| ((a, cy, ac), daa_reference(a, cy, ac)))
+        .collect::<Vec<_>>();
+
+    assert_eq!(table.len(), 256 * 2 * 2);
+
+    for ((a, cy, ac), (expected_a, expected_cy, expected_ac)) in table {
+        let mut cpu = Cpu::new(|_, _| {});
+        cpu.a = a;
+        cpu.condition_codes.cy = cy;
+        cpu.condition_codes.ac = ac;
+
+        cpu.execute_instruction(DAA)?;
+
+        let case = format!("a: {:#04x}, cy: {}, ac: {}", a, cy, ac);
+        assert_eq!(cpu.a, expected_a, "{}", case);
+        assert_eq!(cpu.condition_codes.cy, expected_cy, "{}", case);
+        assert_eq!(cpu.condition_codes.ac, expected_ac, "{}", case);
+        assert_eq!(cpu.condition_codes.z, (expected_a == 0) as u8, "{}", case);
+        assert_eq!(cpu.condition_codes.s, expected_a >> 7, "{}", case);
+        assert_eq!(
+            cpu.condition_codes.p,
+            (expected_a.count_ones() % 2 == 0) as u8,
+            "{}",
+            case
+        );
+    }
+
+    Ok(())
+}
+
+// Undocumented opcodes decode as aliases of NOP, JMP, RET and CALL
+#[test]
+fn undocumented_decode() {
+    for opcode in [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38] {
+        let instruction = Instruction::decode(&[opcode]).unwrap();
+        assert_eq!(instruction, UndocumentedNoOp { opcode });
+        assert!(instruction.is_undocumented());
+        assert_eq!(instruction.op_bytes(), 1);
+    }
+
+    assert_eq!(
+        Instruction::decode(&[0xcb, 0x34, 0x12]),
+        Ok(UndocumentedJMP { address: 0x1234 })
+    );
+    assert_eq!(Instruction::decode(&[0xd9]), Ok(UndocumentedRET));
+    for opcode in [0xdd, 0xed, 0xfd] {
+        assert_eq!(
+            Instruction::decode(&[opcode, 0x34, 0x12]),
+            Ok(UndocumentedCALL {
+                opcode,
+                address: 0x1234
+            })
+        );
+    }
+
+    // Every opcode now decodes to something
+    for opcode in 0..=0xffu8 {
+        assert!(Instruction::decode(&[opcode, 0, 0]).is_ok());
+    }
+
+    assert!(!NoOp.is_undocumented());
+    assert_eq!(
+        format!("{}", UndocumentedNoOp { opcode: 0x10 }),
+        "*NOP (0x10)"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            UndocumentedCALL {
+                opcode: 0xdd,
+                address: 0x1234
+            }
+        ),
+        "*CALL 0x1234 (0xdd)"
+    );
+}
+
+// Undocumented opcodes execute like the instructions they alias
+#[test]
+fn undocumented_execute() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    let mut program = vec![0; 0x24];
+    // *NOP; *JMP 0x0010
+    program[0..4].copy_from_slice(&[0x08, 0xcb, 0x10, 0x00]);
+    // *CALL 0x0020; HLT
+    program[0x10..0x14].copy_from_slice(&[0xdd, 0x20, 0x00, 0x76]);
+    // *RET
+    program[0x20] = 0xd9;
+    cpu.load_into_memory(program)?;
+    cpu.sp = 0x100;
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0001);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0010);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0020);
+    assert_eq!(cpu.sp, 0x00fe);
+
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0013);
+    assert_eq!(cpu.sp, 0x0100);
+
+    cpu.step()?;
+    assert!(cpu.halted());
+
+    Ok(())
+}
+
+// The operands of an instruction at the end of memory wrap around to the
+// start, as they do on the 8080
+#[test]
+fn operands_wrap_around_memory() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.pc = 0xfffe;
+    cpu.memory[0xfffe] = 0xc3;
+    cpu.memory[0xffff] = 0x34;
+    cpu.memory[0x0000] = 0x12;
+
+    assert_eq!(cpu.next_instruction()?, Some(JMP { address: 0x1234 }));
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x1234);
+
+    Ok(())
+}
+
+// Register operands that make no sense are errors rather than panics
+#[test]
+fn invalid_register() {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    assert_eq!(
+        cpu.execute_instruction(LXI {
+            register: Reg::A,
+            value: 0x1234
+        }),
+        Err(cpu::Error::InvalidRegister(Reg::A))
+    );
+    assert_eq!(
+        cpu.execute_instruction(INX { register: Reg::Psw }),
+        Err(cpu::Error::InvalidRegister(Reg::Psw))
+    );
+    assert_eq!(
+        cpu.execute_instruction(MVI {
+            register: Reg::SP,
+            value: 0x12
+        }),
+        Err(cpu::Error::InvalidRegister(Reg::SP))
+    );
+    assert_eq!(
+        cpu.execute_instruction(ADD { register: Reg::Psw }),
+        Err(cpu::Error::InvalidRegister(Reg::Psw))
+    );
+}
+
+// Pushing below the stack limit is an error
+#[test]
+fn stack_overflow() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    cpu.sp = 0x2004;
+    cpu.set_stack_limit(Some(0x2000));
+
+    cpu.execute_instruction(PUSH { register: Reg::B })?;
+    cpu.execute_instruction(CALL { address: 0x0100 })?;
+
+    assert_eq!(cpu.sp, 0x2000);
+    assert_eq!(
+        cpu.execute_instruction(RST { data: 1 }),
+        Err(cpu::Error::StackOverflow { sp: 0x1ffe })
+    );
+    assert_eq!(cpu.sp, 0x2000);
+    assert_eq!(cpu.pc, 0x0100);
+
+    cpu.set_stack_limit(None);
+    cpu.execute_instruction(RST { data: 1 })?;
+
+    assert_eq!(cpu.sp, 0x1ffe);
+
+    Ok(())
+}
+
+// A push that wraps the sp around past 0x0000 is below the limit, but one
+// from an sp of 0 uses the top of memory
+#[test]
+fn stack_overflow_wraps() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.set_stack_limit(Some(0x2000));
+
+    cpu.sp = 0x0001;
+    assert_eq!(
+        cpu.execute_instruction(PUSH { register: Reg::B }),
+        Err(cpu::Error::StackOverflow { sp: 0xffff })
+    );
+    assert_eq!(cpu.sp, 0x0001);
+
+    cpu.sp = 0x0000;
+    cpu.execute_instruction(PUSH { register: Reg::B })?;
+    assert_eq!(cpu.sp, 0xfffe);
+
+    Ok(())
+}
+
+/// Clock cycles for each opcode, when conditional calls and returns aren't
+/// taken
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+    4,  10, 7,  5,  5,  5,  7,  4,  4,  10, 7,  5,  5,  5,  7,  4,  // 0
+    4,  10, 7,  5,  5,  5,  7,  4,  4,  10, 7,  5,  5,  5,  7,  4,  // 1
+    4,  10, 16, 5,  5,  5,  7,  4,  4,  10, 16, 5,  5,  5,  7,  4,  // 2
+    4,  10, 13, 5,  10, 10, 10, 4,  4,  10, 13, 5,  5,  5,  7,  4,  // 3
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 4
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 5
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 6
+    7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5,  // 7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // A
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // B
+    5,  10, 10, 10, 11, 11, 7,  11, 5,  10, 10, 10, 11, 17, 7,  11, // C
+    5,  10, 10, 10, 11, 11, 7,  11, 5,  10, 10, 10, 11, 17, 7,  11, // D
+    5,  10, 10, 18, 11, 11, 7,  11, 5,  5,  10, 4,  11, 17, 7,  11, // E
+    5,  10, 10, 4,  11, 11, 7,  11, 5,  5,  10, 4,  11, 17, 7,  11, // F
+];
+
+// Every opcode takes the documented number of cycles
+#[test]
+fn instruction_cycles() {
+    for opcode in 0..=0xffu8 {
+        let instruction = Instruction::decode(&[opcode, 0, 0]).unwrap();
+        assert_eq!(
+            instruction.cycles(),
+            CYCLES[opcode as usize],
+            "{:#04x} {}",
+            opcode,
+            instruction
+        );
+    }
+
+    assert_eq!(CNZ { address: 0 }.cycles_taken(), 17);
+    assert_eq!(RZ.cycles_taken(), 11);
+    // Conditional jumps take the same time either way
+    assert_eq!(JNZ { address: 0 }.cycles_taken(), 10);
+    assert_eq!(CALL { address: 0 }.cycles_taken(), 17);
+}
+
+// The cycle counter advances by the cost of each instruction stepped
+#[test]
+fn step_cycles() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // NOP; MVI M, 0x01; CZ 0x0000; CNZ 0x0010
+    cpu.load_into_memory(vec![0x00, 0x36, 0x01, 0xcc, 0x00, 0x00, 0xc4, 0x10, 0x00])?;
+    cpu.sp = 0x100;
+    cpu.condition_codes.z = 0;
+
+    assert_eq!(cpu.cycles(), 0);
+
+    cpu.step()?;
+    assert_eq!(cpu.cycles(), 4);
+
+    cpu.step()?;
+    assert_eq!(cpu.cycles(), 14);
+
+    // Not taken
+    cpu.step()?;
+    assert_eq!(cpu.cycles(), 25);
+
+    // Taken
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0010);
+    assert_eq!(cpu.cycles(), 42);
+
+    // RNZ, taken
+    cpu.memory[0x10] = 0xc0;
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0009);
+    assert_eq!(cpu.cycles(), 53);
+
+    Ok(())
+}
+
+// Running for a budget of cycles stops after the instruction that exhausts it
+#[test]
+fn run_for() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // NOP (4); LXI B, 0 (10); NOP (4); HLT (7)
+    cpu.load_into_memory(vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x76])?;
+
+    assert_eq!(cpu.run_for(4)?, 4);
+    assert_eq!(cpu.pc, 0x0001);
+
+    // LXI overshoots the budget
+    assert_eq!(cpu.run_for(5)?, 10);
+    assert_eq!(cpu.pc, 0x0004);
+    assert_eq!(cpu.cycles(), 14);
+
+    // Once halted, the rest of the budget is spent idling
+    assert_eq!(cpu.run_for(100)?, 100);
+    assert!(cpu.halted());
+    assert_eq!(cpu.cycles(), 114);
+
+    // The largest budget runs until the cycle count can't go any higher
+    assert_eq!(cpu.run_for(u64::MAX)?, u64::MAX - 114);
+    assert_eq!(cpu.cycles(), u64::MAX);
+
+    Ok(())
+}
+
+// Writes to ROM are ignored unless the bus is set up to report them
+#[test]
+fn mapped_memory_rom() -> Result<(), cpu::Error> {
+    let rom = || MappedMemory::new().rom(0x0000, vec![0x3e, 0x12, 0x32, 0x00, 0x00]);
+
+    // MVI A, 0x12; STA 0x0000
+    let mut cpu = Cpu::with_memory(|_, _| {}, rom());
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.memory().peek(0x0000), 0x3e);
+
+    let mut cpu = Cpu::with_memory(|_, _| {}, rom().report_rom_writes(true));
+    cpu.step()?;
+    assert_eq!(cpu.step(), Err(cpu::Error::WriteToRom(0x0000)));
+
+    // Loaders can still fill ROM
+    cpu.memory_mut().poke(0x0000, 0x00);
+    assert_eq!(cpu.memory().peek(0x0000), 0x00);
+
+    Ok(())
+}
+
+// Empty ROM maps nothing, and ROM running past 0xffff is cut off there
+#[test]
+fn mapped_memory_rom_bounds() {
+    let mut memory = MappedMemory::new().rom(0x1000, Vec::new());
+    assert_eq!(memory.peek(0x1000), 0xff);
+    assert_eq!(memory.read(0x1000), 0xff);
+
+    let memory = MappedMemory::new().rom(0x0000, vec![0x42; 0x10001]);
+    assert_eq!(memory.peek(0x0001), 0x42);
+    assert_eq!(memory.peek(0xffff), 0x42);
+
+    let memory = MappedMemory::new().rom(0xfffe, vec![1, 2, 3]);
+    assert_eq!(memory.peek(0xffff), 2);
+    assert_eq!(memory.peek(0x0000), 0xff);
+}
+
+// Mirrored ranges alias their target, and unmapped addresses read as 0xff
+#[test]
+fn mapped_memory_mirror() -> Result<(), cpu::Error> {
+    let mut memory = MappedMemory::new()
+        .ram(0x2000..=0x20ff)
+        .mirror(0x2100..=0x24ff, 0x2000..=0x20ff);
+
+    memory.write(0x2001, 0x42)?;
+    assert_eq!(memory.peek(0x2101), 0x42);
+    assert_eq!(memory.peek(0x2401), 0x42);
+
+    memory.write(0x2302, 0x24)?;
+    assert_eq!(memory.peek(0x2002), 0x24);
+
+    memory.write(0x8000, 0x24)?;
+    assert_eq!(memory.peek(0x8000), 0xff);
+
+    // A mirror of nothing maps nothing
+    let mut memory =
+        MappedMemory::new().mirror(0x3000..=0x30ff, std::ops::RangeInclusive::new(5, 4));
+    assert_eq!(memory.read(0x3000), 0xff);
+    memory.write(0x3000, 0x24)?;
+
+    Ok(())
+}
+
+// Devices see CPU accesses as offsets into their region
+#[test]
+fn mapped_memory_device() -> Result<(), cpu::Error> {
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+        reads: u8,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.reads += 1;
+            self.peek(offset)
+        }
+
+        fn peek(&self, offset: u16) -> u8 {
+            self.value.wrapping_add(offset as u8)
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.value = value;
+        }
+    }
+
+    let memory = MappedMemory::new()
+        .ram(0x0000..=0x00ff)
+        .device(0x4000..=0x4001, Latch::default());
+    let mut cpu = Cpu::with_memory(|_, _| {}, memory);
+
+    // MVI A, 0x10; STA 0x4000; LDA 0x4001
+    cpu.load_into_memory(vec![0x3e, 0x10, 0x32, 0x00, 0x40, 0x3a, 0x01, 0x40])?;
+    cpu.step()?;
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.a, 0x11);
+
+    Ok(())
+}
+
+// Snapshots share memory with the CPU they were taken from, but writes on
+// either side stay private to it
+#[test]
+fn memory_snapshot() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // MVI A, 0x42; STA 0x1234
+    cpu.load_into_memory(vec![0x3e, 0x42, 0x32, 0x34, 0x12])?;
+    let snapshot = cpu.clone();
+
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.memory[0x1234], 0x42);
+    assert_eq!(snapshot.memory[0x1234], 0x00);
+    assert_eq!(snapshot.memory[0x0000], 0x3e);
+    assert_ne!(cpu.memory(), snapshot.memory());
+
+    cpu.memory[0x1234] = 0x00;
+    assert_eq!(cpu.memory(), snapshot.memory());
+
+    Ok(())
+}
+
+// A saved state restores registers, flags, interrupt state and memory
+#[test]
+fn save_state_round_trip() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+
+    // LXI SP, 0x2000; MVI A, 0x80; ADD A; EI; PUSH PSW; HLT
+    cpu.load_into_memory(vec![0x31, 0x00, 0x20, 0x3e, 0x80, 0x87, 0xfb, 0xf5, 0x76])?;
+    cpu.write_to_bus(0xc7);
+    while !cpu.halted() {
+        cpu.step()?;
+    }
+
+    let saved = cpu.save_state();
+    let mut restored = Cpu::new(|_, _| {});
+    restored.memory[0x8000] = 0xff;
+    restored.load_state(&saved)?;
+
+    assert_eq!(restored.snapshot(), cpu.snapshot());
+    assert_eq!(restored.pc, cpu.pc);
+    assert_eq!(restored.memory[0x8000], 0x00);
+    assert_eq!(restored.memory[0x1ffe], 0x47);
+    assert_eq!(restored.cycles(), cpu.cycles());
+    assert!(restored.interrupts_enabled());
+
+    Ok(())
+}
+
+// Restoring a save state leaves devices and mirrors alone, and only writes
+// the RAM and ROM behind them
+#[test]
+fn save_state_skips_devices() -> Result<(), cpu::Error> {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct Counter(Rc<Cell<usize>>);
+
+    impl Device for Counter {
+        fn peek(&self, _offset: u16) -> u8 {
+            0x55
+        }
+
+        fn write(&mut self, _offset: u16, _value: u8) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let writes = Rc::new(Cell::new(0));
+    let memory = MappedMemory::new()
+        .rom(0x0000, vec![0x76])
+        .ram(0x2000..=0x20ff)
+        .mirror(0x2100..=0x21ff, 0x2000..=0x20ff)
+        .device(0x4000..=0x4003, Counter(writes.clone()));
+    let mut cpu = Cpu::with_memory(|_, _| {}, memory);
+    cpu.memory_mut().poke(0x2010, 0x42);
+
+    let state = cpu.snapshot();
+    assert_eq!(state.memory[&0x2010][0], 0x42);
+    assert!(!state.memory.contains_key(&0x2110));
+    assert!(!state.memory.contains_key(&0x4000));
+
+    cpu.memory_mut().poke(0x2010, 0x00);
+    cpu.restore(&state);
+    assert_eq!(cpu.memory().peek(0x2010), 0x42);
+    assert_eq!(cpu.memory().peek(0x2110), 0x42);
+    assert_eq!(cpu.memory().peek(0x0000), 0x76);
+    assert_eq!(writes.get(), 0);
+
+    Ok(())
+}
+
+// Chunks this version doesn't know about are skipped, and ones an older
+// version didn't write are left at their defaults
+#[test]
+fn save_state_unknown_and_missing_chunks() -> Result<(), cpu::Error> {
+    let mut bytes = b"8080\x01\x00".to_vec();
+    bytes.extend(b"NEW \x02\x00\x00\x00\xaa\xbb");
+    bytes.extend(b"REGS\x0c\x00\x00\x00\x01\x02\x03\x04\x05\x06\x07\x34\x12\x00\x20\xcc");
+
+    let state = SaveState::from_bytes(&bytes)?;
+    assert_eq!(state.a, 0x01);
+    assert_eq!(state.l, 0x07);
+    assert_eq!(state.pc, 0x1234);
+    assert_eq!(state.sp, 0x2000);
+    assert_eq!(state.cycles, 0);
+    assert!(state.memory.is_empty());
+
+    assert_eq!(
+        SaveState::from_bytes(b"8080\x02\x00"),
+        Err(cpu::Error::InvalidSaveState("saved by a newer version"))
+    );
+    assert_eq!(
+        SaveState::from_bytes(b"8080\x01\x00REGS\x0b\x00"),
+        Err(cpu::Error::InvalidSaveState("unexpected end of data"))
+    );
+
+    let mut bytes = b"8080\x01\x00MEM \x12\x00\x00\x00\x08\x00".to_vec();
+    bytes.extend([0x42; 16]);
+    assert_eq!(
+        SaveState::from_bytes(&bytes),
+        Err(cpu::Error::InvalidSaveState("unaligned memory row"))
+    );
+
+    Ok(())
+}
+
+// The human readable form shows memory as rows of hex
+#[cfg(feature = "serde")]
+#[test]
+fn save_state_serde() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_into_memory(vec![0x3e, 0x42])?;
+    cpu.step()?;
+
+    let state = cpu.snapshot();
+    let json = serde_json::to_value(&state).unwrap();
+    assert_eq!(json["a"], 0x42);
+    assert_eq!(
+        json["memory"]["0x0000"],
+        "3e 42 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+    );
+
+    let parsed: SaveState = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed, state);
+
+    // Rows must start at a multiple of 16
+    let mut unaligned = json;
+    unaligned["memory"] = serde_json::json!({
+        "0x0008": "3e 42 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+    });
+    let err = serde_json::from_value::<SaveState>(unaligned).unwrap_err();
+    assert_eq!(err.to_string(), "invalid save state: unaligned memory row");
+
+    Ok(())
+}
+
+// Stepping back undoes register changes and memory writes
+#[test]
+fn step_back() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.set_journal_limit(100);
+
+    // LXI SP, 0x2000; MVI A, 0x42; STA 0x1234; PUSH PSW; HLT
+    cpu.load_into_memory(vec![
+        0x31, 0x00, 0x20, 0x3e, 0x42, 0x32, 0x34, 0x12, 0xf5, 0x76,
+    ])?;
+    let start = cpu.snapshot();
+    while !cpu.halted() {
+        cpu.step()?;
+    }
+    assert_eq!(cpu.journal_len(), 5);
+
+    assert!(cpu.step_back());
+    assert!(!cpu.halted());
+    assert_eq!(cpu.pc, 0x0009);
+
+    assert!(cpu.step_back());
+    assert_eq!(cpu.sp, 0x2000);
+    assert_eq!(cpu.memory[0x1fff], 0x00);
+    assert_eq!(cpu.memory[0x1ffe], 0x00);
+
+    assert!(cpu.step_back());
+    assert!(cpu.step_back());
+    assert!(cpu.step_back());
+    assert!(!cpu.step_back());
+    assert_eq!(cpu.snapshot(), start);
+
+    Ok(())
+}
+
+// Undoing an acknowledged interrupt puts the request back in the queue
+#[test]
+fn step_back_interrupt() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.set_journal_limit(10);
+
+    // EI; NOP
+    cpu.load_into_memory(vec![0xfb, 0x00])?;
+    cpu.sp = 0x100;
+    cpu.step()?;
+    cpu.step()?;
+    cpu.generate_interrupt(1);
+    cpu.step()?;
+    assert_eq!(cpu.pc, 0x0008);
+    assert_eq!(cpu.pending_interrupts().count(), 0);
+
+    assert!(cpu.step_back());
+    assert_eq!(cpu.pc, 0x0002);
+    assert_eq!(cpu.sp, 0x100);
+    assert!(cpu.interrupts_enabled());
+    assert_eq!(
+        cpu.pending_interrupts().collect::<Vec<_>>(),
+        [&RST { data: 1 }]
+    );
+
+    Ok(())
+}
+
+// Reverse continue stops when the pc reaches the target, and the journal
+// only holds the most recent steps
+#[test]
+fn reverse_continue_to() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.set_journal_limit(4);
+
+    // INR A; JMP 0x0000
+    cpu.load_into_memory(vec![0x3c, 0xc3, 0x00, 0x00])?;
+    for _ in 0..10 {
+        cpu.step()?;
+    }
+    assert_eq!(cpu.a, 5);
+    assert_eq!(cpu.journal_len(), 4);
+
+    assert!(cpu.reverse_continue_to(0x0001));
+    assert_eq!(cpu.a, 5);
+    assert_eq!(cpu.journal_len(), 3);
+
+    assert!(!cpu.reverse_continue_to(0x1234));
+    assert_eq!(cpu.journal_len(), 0);
+    assert_eq!(cpu.a, 3);
+
+    Ok(())
+}
+
+// Machines in the same state compare equal whatever their journals hold,
+// and a clone starts with an empty journal
+#[test]
+fn journal_not_compared() -> Result<(), cpu::Error> {
+    let io: fn(u8, u8) = |_, _| {};
+    let mut journalled = Cpu::new(io);
+    let mut plain = Cpu::new(io);
+    journalled.set_journal_limit(10);
+
+    // INR A; INR A
+    journalled.load_into_memory(vec![0x3c, 0x3c])?;
+    plain.load_into_memory(vec![0x3c, 0x3c])?;
+    for _ in 0..2 {
+        journalled.step()?;
+        plain.step()?;
+    }
+    assert_eq!(journalled.journal_len(), 2);
+    assert_eq!(journalled, plain);
+
+    let mut clone = journalled.clone();
+    assert_eq!(clone, journalled);
+    assert_eq!(clone.journal_len(), 0);
+    clone.step()?;
+    assert_eq!(clone.journal_len(), 1);
+    assert_ne!(clone, journalled);
+
+    Ok(())
+}
+
+// Running stops before an instruction with a breakpoint, and resuming steps
+// over it
+#[test]
+fn debugger_breakpoint() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    let mut debugger = Debugger::new();
+
+    // INR A; JMP 0x0000
+    cpu.load_into_memory(vec![0x3c, 0xc3, 0x00, 0x00])?;
+    debugger.add_breakpoint(0x0001);
+
+    assert_eq!(debugger.run(&mut cpu)?, StopReason::Breakpoint(0x0001));
+    assert_eq!(cpu.a, 1);
+    assert_eq!(debugger.run(&mut cpu)?, StopReason::Breakpoint(0x0001));
+    assert_eq!(cpu.a, 2);
+
+    assert!(debugger.remove_breakpoint(0x0001));
+    assert_eq!(
+        debugger.run_for(&mut cpu, 100)?,
+        StopReason::BudgetExhausted
+    );
+
+    // The largest budget means running until something else stops it
+    debugger.add_breakpoint(0x0001);
+    assert_eq!(
+        debugger.run_for(&mut cpu, u64::MAX)?,
+        StopReason::Breakpoint(0x0001)
+    );
+
+    Ok(())
+}
+
+// Watchpoints stop after an instruction reads or writes the watched range
+#[test]
+fn debugger_watchpoint() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    let mut debugger = Debugger::new();
+
+    // LXI H, 0x2001; MOV A, M; MVI M, 0x42; HLT
+    cpu.load_into_memory(vec![0x21, 0x01, 0x20, 0x7e, 0x36, 0x42, 0x76])?;
+    debugger.add_watchpoint(0x2000..=0x200f, WatchKind::Write);
+
+    assert_eq!(
+        debugger.run(&mut cpu)?,
+        StopReason::Watchpoint {
+            address: 0x2001,
+            kind: AccessKind::Write,
+            value: 0x42,
+            watch: WatchKind::Write
+        }
+    );
+    assert_eq!(cpu.pc, 0x0006);
+
+    cpu.pc = 0;
+    debugger.add_watchpoint(0x2001..=0x2001, WatchKind::Access);
+    assert_eq!(
+        debugger.run(&mut cpu)?,
+        StopReason::Watchpoint {
+            address: 0x2001,
+            kind: AccessKind::Read,
+            value: 0x42,
+            watch: WatchKind::Access
+        }
+    );
+    assert_eq!(cpu.pc, 0x0004);
+
+    Ok(())
+}
+
+// Port breakpoints stop after IN or OUT, and instruction classes before
+#[test]
+fn debugger_port_and_instruction_breakpoints() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(Ports::new(|port, _| port * 2, |_, _| {}));
+    let mut debugger = Debugger::new();
+
+    // IN 0x10; OUT 0x11; RST 7; HLT
+    cpu.load_into_memory(vec![0xdb, 0x10, 0xd3, 0x11, 0xff, 0x76])?;
+    cpu.sp = 0x100;
+    debugger.add_port_breakpoint(0x11, PortDirection::Any);
+    debugger.add_port_breakpoint(0x10, PortDirection::Out);
+    debugger.add_instruction_breakpoint(InstructionClass::Rst);
+
+    assert_eq!(
+        debugger.run(&mut cpu)?,
+        StopReason::Port {
+            port: 0x11,
+            direction: PortDirection::Out,
+            value: 0x20
+        }
+    );
+    assert_eq!(
+        debugger.run(&mut cpu)?,
+        StopReason::Instruction {
+            pc: 0x0004,
+            instruction: RST { data: 7 },
+            class: InstructionClass::Rst
+        }
+    );
+
+    assert!(debugger.remove_instruction_breakpoint(InstructionClass::Rst));
+    // RST 7 lands on zeroed memory, a run of NOPs up to the HLT at 0x0038
+    cpu.memory[0x38] = 0x76;
+    assert_eq!(debugger.run(&mut cpu)?, StopReason::Halted);
+    assert_eq!(cpu.pc, 0x0039);
+
+    Ok(())
+}
+
+/// Send a packet as GDB would and return the stub's reply
+fn gdb_exchange(stream: &mut std::net::TcpStream, packet: &str) -> String {
+    gdb_send(stream, packet);
+    gdb_reply(stream)
+}
+
+fn gdb_send(stream: &mut std::net::TcpStream, packet: &str) {
+    use std::io::Write;
+
+    let checksum = packet.bytes().fold(0u8, u8::wrapping_add);
+    write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+}
+
+/// Read a packet from the stub, skipping its acknowledgement
+fn gdb_reply(stream: &mut std::net::TcpStream) -> String {
+    use std::io::{Read, Write};
+
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        match byte[0] {
+            b'+' if reply.is_empty() => (),
+            b'#' => break,
+            byte => reply.push(byte),
+        }
+    }
+    let mut checksum = [0; 2];
+    stream.read_exact(&mut checksum).unwrap();
+    stream.write_all(b"+").unwrap();
+
+    String::from_utf8(reply[1..].to_vec()).unwrap()
+}
+
+// A scripted client can inspect and change state, step, and continue to
+// breakpoints
+#[test]
+fn gdb_session() -> Result<(), cpu::Error> {
+    use std::io::Write;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut exchange = |packet| gdb_exchange(&mut stream, packet);
+
+        assert!(exchange("qSupported:swbreak+").contains("qXfer:features:read+"));
+        let xml = exchange("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains(r#"<reg name="psw" bitsize="16"/>"#));
+
+        assert_eq!(exchange("g"), "00000000000000000000000200");
+        assert_eq!(exchange("s"), "S05");
+        assert_eq!(exchange("p0"), "42");
+        assert_eq!(exchange("p8"), "0200");
+
+        assert_eq!(exchange("Z0,5,1"), "OK");
+        assert_eq!(exchange("c"), "T05swbreak:;");
+        assert_eq!(exchange("p8"), "0500");
+        assert_eq!(exchange("m2000,2"), "4200");
+        assert_eq!(exchange("mffff,2"), "00");
+        assert_eq!(exchange("m10000,1"), "E01");
+
+        assert_eq!(exchange("M2000,2:abcd"), "OK");
+        assert_eq!(exchange("m2000,2"), "abcd");
+        assert_eq!(exchange("P1=7f"), "OK");
+        assert_eq!(exchange("p1"), "7f");
+        assert_eq!(exchange("pa"), "E01");
+
+        // Continuing steps over the breakpoint and comes back round to it
+        assert_eq!(exchange("c"), "T05swbreak:;");
+        assert_eq!(exchange("p0"), "43");
+
+        assert_eq!(exchange("Z2,2000,1"), "OK");
+        assert_eq!(exchange("P8=0200"), "OK");
+        assert_eq!(exchange("c"), "T05watch:2000;");
+        assert_eq!(exchange("m2000,1"), "43");
+        assert_eq!(exchange("z2,2000,1"), "OK");
+        assert_eq!(exchange("Z2,10,ffffffff"), "E01");
+        assert_eq!(exchange("Z2,fff0,11"), "E01");
+
+        // An access watchpoint is reported as one, whatever the access
+        assert_eq!(exchange("Z4,2000,1"), "OK");
+        assert_eq!(exchange("P8=0200"), "OK");
+        assert_eq!(exchange("c"), "T05awatch:2000;");
+        assert_eq!(exchange("z4,2000,1"), "OK");
+
+        // Without breakpoints the loop only stops when interrupted
+        assert_eq!(exchange("z0,5,1"), "OK");
+        gdb_send(&mut stream, "c");
+        stream.write_all(&[0x03]).unwrap();
+        assert_eq!(gdb_reply(&mut stream), "S02");
+
+        assert_eq!(gdb_exchange(&mut stream, "D"), "OK");
+    });
+
+    let mut cpu = Cpu::new(|_, _| {});
+    // MVI A, 0x42; STA 0x2000; INR A; JMP 0x0005
+    cpu.load_into_memory(vec![0x3e, 0x42, 0x32, 0x00, 0x20, 0x3c, 0xc3, 0x05, 0x00])?;
+
+    let mut server = GdbServer::new(cpu);
+    let (stream, _) = listener.accept().unwrap();
+    server.serve(stream).unwrap();
+    client.join().unwrap();
+
+    assert_eq!(server.cpu().b(), 0x7f);
+    assert_eq!(server.cpu().memory()[0x2001], 0xcd);
+
+    Ok(())
+}
+
+// Each traced step records the instruction, registers on either side and
+// the memory it touched
+#[test]
+fn step_traced() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    let mut records: Vec<TraceRecord> = Vec::new();
+    let mut sink = |record: &TraceRecord| records.push(record.clone());
+
+    // LXI SP, 0x0100; EI; PUSH B; HLT
+    cpu.load_into_memory(vec![0x31, 0x00, 0x01, 0xfb, 0xc5, 0x76])?;
+    cpu.b = 0x12;
+    cpu.c = 0x34;
+    for _ in 0..4 {
+        cpu.step_traced(&mut sink)?;
+    }
+    // Halted, so nothing is recorded until the interrupt
+    cpu.step_traced(&mut sink)?;
+    cpu.generate_interrupt(2);
+    cpu.step_traced(&mut sink)?;
+
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[0].bytes, [0x31, 0x00, 0x01]);
+    assert_eq!(
+        records[0].instruction,
+        LXI {
+            register: Reg::SP,
+            value: 0x0100
+        }
+    );
+    assert_eq!(records[0].before.sp, 0x0000);
+    assert_eq!(records[0].after.sp, 0x0100);
+    assert_eq!(records[0].instruction_cycles, 10);
+
+    assert_eq!(records[2].pc, 0x0004);
+    assert_eq!(records[2].cycles, 14);
+    assert_eq!(
+        records[2].memory_accesses,
+        [
+            MemoryAccess {
+                address: 0x00ff,
+                kind: AccessKind::Write,
+                value: 0x12
+            },
+            MemoryAccess {
+                address: 0x00fe,
+                kind: AccessKind::Write,
+                value: 0x34
+            },
+        ]
+    );
+
+    assert_eq!(records[4].instruction, RST { data: 2 });
+    assert!(records[4].bytes.is_empty());
+    assert_eq!(records[4].pc, 0x0006);
+    assert_eq!(records[4].after.pc, 0x0010);
+
+    Ok(())
+}
+
+// The text format has one line per instruction with the registers before it
+#[test]
+fn text_trace() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    let mut writer = TextTraceWriter::new(Vec::new());
+
+    // MVI A, 0x80; ADD A; HLT
+    cpu.load_into_memory(vec![0x3e, 0x80, 0x87, 0x76])?;
+    while !cpu.halted() {
+        cpu.step_traced(&mut writer)?;
+    }
+
+    let text = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        text,
+        "PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\t(3E 80)\n\
+         PC: 0002, AF: 8002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 7\t(87)\n\
+         PC: 0003, AF: 0047, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 11\t(76)\n"
+    );
+
+    Ok(())
+}
+
+// Binary records have a fixed layout after a header
+#[test]
+fn binary_trace() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    let mut writer = BinaryTraceWriter::new(Vec::new());
+
+    // STA 0x1234; NOP
+    cpu.load_into_memory(vec![0x32, 0x34, 0x12, 0x00])?;
+    cpu.a = 0x56;
+    cpu.step_traced(&mut writer)?;
+    cpu.step_traced(&mut writer)?;
+
+    let bytes = writer.into_inner().unwrap();
+    let (header, records) = bytes.split_at(8);
+    assert_eq!(header, b"8080TRC\x01");
+    // Two records of 40 bytes plus the first one's memory access
+    assert_eq!(records.len(), 40 * 2 + 4);
+    assert_eq!(&records[..6], [0x00, 0x00, 0x03, 0x32, 0x34, 0x12]);
+    assert_eq!(&records[38..44], [13, 1, 1, 0x34, 0x12, 0x56]);
+
+    Ok(())
+}
+
+// Disassembly yields a line per instruction at its address, with the opcode
+// of an instruction cut off by the end of the input as data
+#[test]
+fn disassemble_lines() {
+    let lines: Vec<_> = disassemble(&[0x00, 0x3e, 0x42, 0xc3, 0x34], 0x0100).collect();
+
+    assert_eq!(
+        lines,
+        [
+            DisassembledLine {
+                address: 0x0100,
+                bytes: vec![0x00],
+                instruction: Some(NoOp)
+            },
+            DisassembledLine {
+                address: 0x0101,
+                bytes: vec![0x3e, 0x42],
+                instruction: Some(MVI {
+                    register: Reg::A,
+                    value: 0x42
+                })
+            },
+            DisassembledLine {
+                address: 0x0103,
+                bytes: vec![0xc3],
+                instruction: None
+            },
+            DisassembledLine {
+                address: 0x0104,
+                bytes: vec![0x34],
+                instruction: Some(INR { register: Reg::M })
+            },
+        ]
+    );
+
+    assert_eq!(lines[0].to_string(), "0x0100 00       NOP");
+    assert_eq!(lines[1].to_string(), "0x0101 3e 42    MVI A 0x42");
+    assert_eq!(lines[2].to_string(), "0x0103 c3       DB 0xc3");
+}
+
+// Following the control flow from an entry point skips over the data between
+// instructions, and reports jumps through PCHL
+#[test]
+fn analyze_follows_control_flow() {
+    let program = [
+        0xcd, 0x08, 0x01, // CALL 0x0108
+        0xc3, 0x0e, 0x01, // JMP 0x010e
+        0x12, 0x34, // Data
+        0xca, 0x0d, 0x01, // JZ 0x010d
+        0xe9, // PCHL
+        0xff, // Data
+        0xc9, // RET
+        0x76, // HLT
+        0x01, // Data cut off by the end
+    ];
+    let analysis = analyze(&program, 0x0100, &[0x0100]);
+
+    let code: Vec<_> = (0x0100..0x0110)
+        .filter(|&address| analysis.is_code(address))
+        .collect();
+    assert_eq!(
+        code,
+        [
+            0x0100, 0x0101, 0x0102, 0x0103, 0x0104, 0x0105, 0x0108, 0x0109, 0x010a, 0x010b, 0x010d,
+            0x010e
+        ]
+    );
+    assert_eq!(analysis.unresolved_jumps().collect::<Vec<_>>(), [0x010b]);
+    assert_eq!(
+        analysis.instruction_at(0x0108),
+        Some(JZ { address: 0x010d })
+    );
+    assert_eq!(analysis.instruction_at(0x0106), None);
+
+    let lines: Vec<_> = analysis.lines().map(|line| line.to_string()).collect();
+    assert_eq!(
+        lines,
+        [
+            "0x0100 cd 08 01 CALL 0x0108",
+            "0x0103 c3 0e 01 JMP 0x010e",
+            "0x0106 12       DB 0x12",
+            "0x0107 34       DB 0x34",
+            "0x0108 ca 0d 01 JZ 0x010d",
+            "0x010b e9       PCHL",
+            "0x010c ff       DB 0xff",
+            "0x010d c9       RET",
+            "0x010e 76       HLT",
+            "0x010f 01       DB 0x01",
+        ]
+    );
+}
+
+// Without entry points, the reset and RST vectors are followed
+#[test]
+fn analyze_rst_vectors() {
+    let mut rom = [0xff; 0x40];
+    for vector in (0..0x38).step_by(8) {
+        rom[vector] = 0xc9; // RET
+    }
+    rom[0x38..0x3b].copy_from_slice(&[0xc3, 0x00, 0x00]); // JMP 0x0000
+
+    let analysis = analyze(&rom, 0, &[]);
+
+    let code: Vec<_> = (0..0x40)
+        .filter(|&address| analysis.is_code(address))
+        .collect();
+    assert_eq!(
+        code,
+        [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0x39, 0x3a]
+    );
+    assert_eq!(analysis.instruction_at(0x38), Some(JMP { address: 0x0000 }));
+    assert_eq!(analysis.instructions().count(), 8);
+}
+
+// Symbol files can have the name or the value first, in the usual notations
+#[test]
+fn parse_symbol_file() {
+    let symbols = SymbolTable::parse(
+        "; Symbols\n\
+         PrintString EQU 1A2BH\n\
+         Start: = $0100\n\
+         \n\
+         Buffer 0x2000 ; Input buffer\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        symbols.iter().collect::<Vec<_>>(),
+        [
+            (0x0100, "Start"),
+            (0x1a2b, "PrintString"),
+            (0x2000, "Buffer")
+        ]
+    );
+    assert_eq!(symbols.address("Buffer"), Some(0x2000));
+    assert_eq!(symbols.name(0x0100), Some("Start"));
+
+    let symbols = SymbolTable::parse("0005 BDOS\n0100H Start:\n").unwrap();
+    assert_eq!(
+        symbols.iter().collect::<Vec<_>>(),
+        [(0x0005, "BDOS"), (0x0100, "Start")]
+    );
+
+    assert_eq!(
+        SymbolTable::parse("Start 0100\nStart\n"),
+        Err(cpu::Error::InvalidSymbolFile { line: 2 })
+    );
+    assert_eq!(
+        SymbolTable::parse("Start 0100\n0005 BDOS\n"),
+        Err(cpu::Error::InvalidSymbolFile { line: 2 })
+    );
+}
+
+// The column order is decided once per file, so a line that could be read
+// either way follows the lines around it
+#[test]
+fn symbol_file_column_order() {
+    let symbols = SymbolTable::parse("BEEF ABC\n0005 BDOS\n").unwrap();
+    assert_eq!(symbols.name(0xbeef), Some("ABC"));
+    assert_eq!(symbols.address("BEEF"), None);
+
+    let symbols = SymbolTable::parse("BEEF ABC\nBDOS 0005\n").unwrap();
+    assert_eq!(symbols.name(0x0abc), Some("BEEF"));
+
+    // With nothing to go on, the name comes first
+    let symbols = SymbolTable::parse("BEEF ABC\n").unwrap();
+    assert_eq!(symbols.name(0x0abc), Some("BEEF"));
+}
+
+// Disassembly with symbols names operands and adds label lines, with
+// generated names for branch targets without one
+#[test]
+fn disassemble_with_symbols() {
+    let program = [
+        0x21, 0x0a, 0x01, // LXI H, Message
+        0xcd, 0x09, 0x01, // CALL 0x0109
+        0xc3, 0x00, 0x01, // JMP 0x0100
+        0xc9, // RET
+        0x48, // Data
+    ];
+    let analysis = analyze(&program, 0x0100, &[0x0100]);
+    let mut symbols = SymbolTable::parse("Message EQU 010AH").unwrap();
+    symbols.add_generated_labels(&analysis);
+
+    let lines: Vec<_> = analysis
+        .lines()
+        .map(|line| line.display_with(&symbols).to_string())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "L_0100:\n0x0100 21 0a 01 LXI H, Message",
+            "0x0103 cd 09 01 CALL SUB_0109",
+            "0x0106 c3 00 01 JMP L_0100",
+            "SUB_0109:\n0x0109 c9       RET",
+            "Message:\n0x010a 48       DB 0x48",
+        ]
+    );
+}
+
+// The debugger breaks on symbols and names addresses when describing stops
+#[test]
+fn debugger_symbols() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_into_memory(vec![
+        0xcd, 0x04, 0x00, // CALL PrintString
+        0x76, // HLT
+        0xc9, // RET
+    ])?;
+
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(SymbolTable::parse("PrintString 0004").unwrap());
+    assert!(debugger.add_breakpoint_at("PrintString"));
+    assert!(!debugger.add_breakpoint_at("Missing"));
+
+    let reason = debugger.run(&mut cpu)?;
+    assert_eq!(reason, StopReason::Breakpoint(0x0004));
+    assert_eq!(debugger.describe(&reason), "Breakpoint at PrintString");
+
+    let reason = StopReason::Instruction {
+        pc: 0x0000,
+        instruction: CALL { address: 0x0004 },
+        class: InstructionClass::Call,
+    };
+    assert_eq!(
+        debugger.describe(&reason),
+        "Call instruction at 0x0000: CALL PrintString"
+    );
+
+    Ok(())
+}
+
+// Encoding any decoded instruction gives back the bytes it was decoded from
+#[test]
+fn encode_round_trip() {
+    for opcode in 0..=0xff {
+        let bytes = [opcode, 0x34, 0x12];
+        let instruction = Instruction::decode(&bytes).unwrap();
+        let length = instruction.op_bytes().into();
+        assert_eq!(
+            instruction.encode().unwrap(),
+            bytes[..length],
+            "{}",
+            instruction
+        );
+    }
+
+    assert_eq!(
+        LXI {
+            register: Reg::A,
+            value: 0
+        }
+        .encode(),
+        Err(cpu::Error::InvalidRegister(Reg::A))
+    );
+    assert_eq!(
+        PUSH { register: Reg::SP }.encode(),
+        Err(cpu::Error::InvalidRegister(Reg::SP))
+    );
+    assert_eq!(
+        UndocumentedNoOp { opcode: 0x3e }.encode(),
+        Err(cpu::Error::InvalidAlias(0x3e))
+    );
+    assert_eq!(
+        UndocumentedCALL {
+            opcode: 0xcd,
+            address: 0
+        }
+        .encode(),
+        Err(cpu::Error::InvalidAlias(0xcd))
+    );
+}
+
+// Labels, directives and expressions assemble to the right bytes
+#[test]
+fn assemble_program() {
+    let assembly = assemble(
+        "\
+CR      EQU 0DH
+        ORG 100H
+Start:  LXI H,Message   ; Forward reference
+        MVI A,'a'-20H
+        CALL Print
+        JMP $
+Print   MOV M,A
+        RET
+Message:
+        DB 'Hi, ''you''',CR,0
+Table:  DW Start,Print+1
+        DS 2
+        DB HIGH Table,LOW(Table),NOT 0 AND 0FFH,10 MOD 3,2 SHL 3
+        END Start
+        DB 99
+",
+    )
+    .unwrap();
+
+    assert_eq!(assembly.origin, 0x0100);
+    assert_eq!(assembly.entry, Some(0x0100));
+    assert_eq!(
+        assembly.bytes,
+        [
+            0x21, 0x0d, 0x01, // LXI H,Message
+            0x3e, 0x41, // MVI A,'A'
+            0xcd, 0x0b, 0x01, // CALL Print
+            0xc3, 0x08, 0x01, // JMP $
+            0x77, // MOV M,A
+            0xc9, // RET
+            b'H', b'i', b',', b' ', b'\'', b'y', b'o', b'u', b'\'', 0x0d, 0x00, // Message
+            0x00, 0x01, 0x0c, 0x01, // Table
+            0x00, 0x00, // DS 2
+            0x01, 0x18, 0xff, 0x01, 0x10,
+        ]
+    );
+    assert_eq!(assembly.symbols.name(0x010b), Some("Print"));
+    assert_eq!(assembly.symbols.address("Table"), Some(0x0118));
+    assert_eq!(assembly.symbols.address("CR"), None);
+
+    assert_eq!(
+        assembly.listing[3].to_string(),
+        "    4 0103 3E 41               MVI A,'a'-20H"
+    );
+    assert_eq!(
+        assembly.listing[0].to_string(),
+        "    1 000D             CR      EQU 0DH"
+    );
+    assert_eq!(assembly.listing.len(), 14);
+}
+
+// Errors name the line they're on
+#[test]
+fn assemble_errors() {
+    let error = |source| assemble(source).unwrap_err().to_string();
+
+    assert_eq!(error("NOP\n JMP Nowhere"), "line 2: Nowhere isn't defined");
+    assert_eq!(error("A1: NOP\nA1: NOP"), "line 2: A1 is already defined");
+    assert_eq!(error(" LXI A,0"), "line 1: A can't be used with LXI");
+    assert_eq!(error(" MVI A,256"), "line 1: 256 doesn't fit in a byte");
+    assert_eq!(error(" DB -129"), "line 1: -129 doesn't fit in a byte");
+    assert_eq!(error(" DW -8001H"), "line 1: -32769 doesn't fit in a word");
+    assert_eq!(assemble(" DB -128,255").unwrap().bytes, [0x80, 0xff]);
+    assert_eq!(error(" MOV A"), "line 1: MOV takes 2 operands");
+    assert_eq!(error(" FOO"), "line 1: unknown instruction FOO");
+    assert_eq!(
+        error(" ORG Later\nLater EQU 5"),
+        "line 1: Later must be defined before it's used"
+    );
+    assert_eq!(
+        error(" ORG 5\n NOP\n ORG 5\n NOP"),
+        "line 4: 0x0005 has already been assembled"
+    );
+    assert_eq!(error(" DW 100000000H * 100000000H"), "line 1: overflow");
+    assert_eq!(
+        error(" DW (-7FFFFFFFFFFFFFFFH - 1) / -1"),
+        "line 1: overflow"
+    );
+    assert_eq!(
+        error(" ORG 0FFFFH\n DB 1,2"),
+        "line 2: assembled past 0xffff"
+    );
+    assert_eq!(
+        error(" ORG 0FFF0H\n DS 10H\n NOP"),
+        "line 3: assembled past 0xffff"
+    );
+    assert_eq!(assemble(" ORG 0FFFFH\n DB 1").unwrap().bytes, [1]);
+    assert_eq!(
+        error(" REPT 10000H\n NOP\n ENDM"),
+        "line 1: 65536 isn't a valid REPT count"
+    );
+    assert_eq!(
+        error(" REPT -1\n NOP\n ENDM"),
+        "line 1: -1 isn't a valid REPT count"
+    );
+}
+
+// An EQU can refer to another EQU further on, even when it's used before
+// either of them
+#[test]
+fn assemble_forward_equ_chain() {
+    let assembly = assemble(" LXI H,X\nX EQU Y+1\nY EQU Z\nZ EQU $").unwrap();
+    assert_eq!(assembly.bytes, [0x21, 0x04, 0x00]);
+
+    assert_eq!(
+        assemble(" LXI H,X\nX EQU Y\nY EQU X")
+            .unwrap_err()
+            .to_string(),
+        "line 1: X is defined in terms of itself"
+    );
+}
+
+// Test programs can be written in assembly rather than hex
+#[test]
+fn run_assembled_program() -> Result<(), cpu::Error> {
+    let assembly = assemble(
+        "\
+        LXI SP,Stack
+        MVI B,5
+        XRA A
+Loop:   ADD B
+        DCR B
+        JNZ Loop
+        STA Result
+        HLT
+Result: DS 1
+        DS 16
+Stack:
+",
+    )
+    .unwrap();
+
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_into_memory(assembly.bytes)?;
+    while !cpu.halted() {
+        cpu.step()?;
+    }
+
+    let result = assembly.symbols.address("Result").unwrap();
+    assert_eq!(cpu.memory()[usize::from(result)], 15);
+    assert_eq!(cpu.sp(), assembly.symbols.address("Stack").unwrap());
+
+    Ok(())
+}
+
+// Macros with parameters and local labels, REPT and nested IFs
+#[test]
+fn assemble_macros() {
+    let assembly = assemble(
+        "\
+DEBUG   EQU 1
+PRINT   MACRO Text, Port
+        LOCAL Skip
+        JMP Skip
+        DB '&Text',0
+Skip:   MVI A,Port
+        ENDM
+Start:  PRINT Hi, 1
+        PRINT <a,b>, 2
+        REPT 3
+        NOP
+        ENDM
+        IF DEBUG
+        IF 0
+        DB 1
+        ELSE
+        DB 2
+        ENDIF
+        ELSE
+        DB 3
+        ENDIF
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        assembly.bytes,
+        [
+            0xc3, 0x06, 0x00, b'H', b'i', 0x00, 0x3e, 0x01, // PRINT Hi, 1
+            0xc3, 0x0f, 0x00, b'a', b',', b'b', 0x00, 0x3e, 0x02, // PRINT <a,b>, 2
+            0x00, 0x00, 0x00, // REPT 3
+            0x02, // IF
+        ]
+    );
+    assert_eq!(assembly.symbols.address("Start"), Some(0x0000));
+
+    // Expanded lines are marked in the listing
+    assert_eq!(
+        assembly.listing[3].to_string(),
+        "    4+0000 C3 06 00            JMP ??0001"
+    );
+}
+
+// Errors show the macros, REPTs and files they're inside
+#[test]
+fn assemble_expansion_errors() {
+    let error = assemble(
+        "\
+INNER   MACRO
+        JMP Missing
+        ENDM
+OUTER   MACRO
+        REPT 2
+        INNER
+        ENDM
+        ENDM
+        OUTER
+",
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 2: Missing isn't defined\n  in macro INNER at line 6\n  in REPT at line 5\n  in macro OUTER at line 9"
+    );
+
+    let error = |source| assemble(source).unwrap_err().to_string();
+    assert_eq!(error(" IF 1\n NOP"), "line 1: IF without ENDIF");
+    assert_eq!(error(" NOP\n ENDIF"), "line 2: ENDIF without IF");
+    assert_eq!(error("M MACRO\n NOP"), "line 1: MACRO without ENDM");
+    assert_eq!(error(" M A,B\n"), "line 1: unknown instruction M");
+    assert_eq!(
+        error("M MACRO X\n ENDM\n M 1,2"),
+        "line 3: M takes 1 arguments"
+    );
+    assert_eq!(
+        assemble("R MACRO\n R\n ENDM\n R").unwrap_err().message,
+        "macros are nested too deeply"
+    );
+}
+
+// INCLUDE reads files relative to the file including them
+#[test]
+fn assemble_include() {
+    let directory = std::env::temp_dir().join(format!("eighty-eighty-{}", std::process::id()));
+    std::fs::create_dir_all(directory.join("lib")).unwrap();
+    std::fs::write(
+        directory.join("main.asm"),
+        " INCLUDE 'lib/defs.asm'\n MVI A,Value\n",
+    )
+    .unwrap();
+    std::fs::write(
+        directory.join("lib/defs.asm"),
+        "Value EQU 42\n INCLUDE bad.asm\n",
+    )
+    .unwrap();
+    std::fs::write(directory.join("lib/bad.asm"), " IF 1\n").unwrap();
+
+    let error = assemble_file(directory.join("main.asm")).unwrap_err();
+    assert_eq!(error.message, "IF without ENDIF");
+    assert!(error.location.file.ends_with("bad.asm"));
+    assert_eq!(error.expansions.len(), 2);
+
+    std::fs::write(directory.join("lib/bad.asm"), "").unwrap();
+    let assembly = assemble_file(directory.join("main.asm")).unwrap();
+    assert_eq!(assembly.bytes, [0x3e, 42]);
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+// Registers and instructions parse from what they display as, and from
+// Intel syntax
+#[test]
+fn parse_instructions() {
+    for register in [
+        Reg::A,
+        Reg::B,
+        Reg::C,
+        Reg::D,
+        Reg::E,
+        Reg::SP,
+        Reg::H,
+        Reg::L,
+        Reg::Psw,
+        Reg::M,
+    ] {
+        assert_eq!(register.to_string().parse(), Ok(register));
+    }
+    assert_eq!("psw".parse(), Ok(Reg::Psw));
+    assert!("X".parse::<Reg>().is_err());
+
+    let parse = |text: &str| text.parse::<Instruction>();
+    let mvi = MVI {
+        register: Reg::B,
+        value: 0x14,
+    };
+    assert_eq!(parse("MVI B 0x14"), Ok(mvi));
+    assert_eq!(parse("MVI B,14H"), Ok(mvi));
+    assert_eq!(parse("  mvi b, 20 "), Ok(mvi));
+    assert_eq!(
+        parse("*CALL 0x1234 (0xfd)"),
+        Ok(UndocumentedCALL {
+            opcode: 0xfd,
+            address: 0x1234
+        })
+    );
+
+    assert_eq!(
+        parse("LXI A, 0"),
+        Err(cpu::Error::InvalidSyntax(
+            "LXI A, 0 isn't a valid instruction".to_owned()
+        ))
+    );
+    assert_eq!(
+        parse("*NOP (0x00)"),
+        Err(cpu::Error::InvalidSyntax(
+            "0x00 isn't an alias of NOP".to_owned()
+        ))
+    );
+    assert!(parse("JMP Label").is_err());
+    assert!(parse("MOV M, M").is_err());
+}
+
+// Every instruction's text round trips, for random operands
+#[test]
+fn instruction_text_round_trip() {
+    // A fixed xorshift generator, so failures can be reproduced
+    let mut state = 0x2545_f491_u32;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+
+    for _ in 0..10_000 {
+        let bytes = random().to_le_bytes();
+        let instruction = Instruction::decode(&bytes).unwrap();
+        let text = instruction.to_string();
+        assert_eq!(text.parse(), Ok(instruction), "{}", text);
+    }
+}
+
+// Intel HEX loads at the addresses in its records and sets the pc from its
+// start address
+#[test]
+fn load_intel_hex() -> Result<(), cpu::Error> {
+    let text = "\
+:030100003E427606
+:0101030000FB
+
+:022000000102DB
+:0400000300000100F8
+:00000001FF
+";
+    assert_eq!(
+        IntelHex::parse(text)?,
+        IntelHex {
+            data: vec![(0x0100, vec![0x3e, 0x42, 0x76, 0x00]), (0x2000, vec![1, 2])],
+            start: Some(0x0100),
+        }
+    );
+
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_intel_hex(text)?;
+    assert_eq!(cpu.pc(), 0x0100);
+    assert_eq!(cpu.memory()[0x0101], 0x42);
+    assert_eq!(cpu.memory()[0x2001], 2);
+
+    cpu.step()?;
+    assert_eq!(cpu.a(), 0x42);
+
+    Ok(())
+}
+
+// Bad Intel HEX is reported with its line and what's wrong with it
+#[test]
+fn intel_hex_errors() {
+    let error = |text: &str| match IntelHex::parse(text) {
+        Err(cpu::Error::InvalidIntelHex { line, error }) => (line, error),
+        result => panic!("{:?}", result),
+    };
+
+    assert_eq!(
+        error(":030100003E427607\n:00000001FF"),
+        (
+            1,
+            IntelHexError::Checksum {
+                expected: 0x06,
+                actual: 0x07
+            }
+        )
+    );
+    assert_eq!(
+        error(":00000001FF\n:03010000XE427606"),
+        (2, IntelHexError::AfterEof)
+    );
+    assert_eq!(
+        error(":03010000XE427606"),
+        (1, IntelHexError::InvalidDigit { column: 10 })
+    );
+    assert_eq!(
+        error(":040100003E427606"),
+        (
+            1,
+            IntelHexError::WrongLength {
+                count: 4,
+                actual: 3
+            }
+        )
+    );
+    assert_eq!(error("030100003E427606"), (1, IntelHexError::MissingColon));
+    assert_eq!(error(":0300"), (1, IntelHexError::Truncated));
+    assert_eq!(
+        error(":020000021000EC"),
+        (1, IntelHexError::UnsupportedRecord(2))
+    );
+    assert_eq!(error(":030100003E427606\n"), (1, IntelHexError::MissingEof));
+    assert_eq!(
+        cpu::Error::InvalidIntelHex {
+            line: 3,
+            error: IntelHexError::Checksum {
+                expected: 0x06,
+                actual: 0x07
+            }
+        }
+        .to_string(),
+        "invalid Intel HEX on line 3: checksum is 0x07 but should be 0x06"
+    );
+}
+
+// Memory written out as Intel HEX reads back the same
+#[test]
+fn write_intel_hex() -> Result<(), cpu::Error> {
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_into_memory((0..0x40).collect())?;
+
+    let hex = IntelHex::from_memory(cpu.memory(), [0x10..=0x23], Some(0x0100));
+    let text = hex.to_string();
+    assert_eq!(
+        text,
+        "\
+:10001000101112131415161718191A1B1C1D1E1F68
+:040020002021222356
+:0400000300000100F8
+:00000001FF
+"
+    );
+    assert_eq!(IntelHex::parse(&text)?, hex);
+
+    Ok(())
+}
+
+// An image loads each of its segments and starts at its entry point, like a
+// CP/M .COM file at 0x0100 with a ROM at the top of memory
+#[test]
+fn load_image() -> Result<(), cpu::Error> {
+    let mut image = Image::new();
+    image.add_segment(0x0100, vec![0x3e, 0x42, 0xcd, 0x00, 0xf0])?;
+    image.add_segment(0xf000, vec![0x3c, 0x76])?;
+    image.add_segment(0xfffe, vec![0xaa, 0xbb])?;
+    image.set_entry(0x0100);
+
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.load_image(&image);
+    assert_eq!(cpu.pc(), 0x0100);
+    assert_eq!(cpu.memory()[0xffff], 0xbb);
+
+    for _ in 0..4 {
+        cpu.step()?;
+    }
+    assert_eq!(cpu.a(), 0x43);
+    assert!(cpu.halted());
+
+    // Without an entry point the pc stays where it was
+    let mut cpu = Cpu::new(|_, _| {});
+    cpu.set_pc(0x1234);
+    cpu.load_image(&Image::new());
+    assert_eq!(cpu.pc(), 0x1234);
+
+    Ok(())
+}
+
+// Segments that overlap or run past the end of memory are rejected
+#[test]
+fn image_segment_errors() -> Result<(), cpu::Error> {
+    let mut image = Image::new();
+    image.add_segment(0x0100, vec![0; 0x10])?;
+    image.add_segment(0x0110, vec![0; 0x10])?;
+    image.add_segment(0x00f0, vec![0; 0x10])?;
+
+    assert_eq!(
+        image.add_segment(0x010f, vec![0; 2]),
+        Err(cpu::Error::OverlappingSegments { address: 0x010f })
+    );
+    assert_eq!(
+        image.add_segment(0x0000, vec![0; 0x200]),
+        Err(cpu::Error::OverlappingSegments { address: 0x00f0 })
+    );
+    assert_eq!(
+        image.add_segment(0xffff, vec![0; 2]),
+        Err(cpu::Error::OutOfMemory)
+    );
+    // Empty segments don't overlap anything, even inside another segment
+    image.add_segment(0x0108, Vec::new())?;
+    image.add_segment(0x0120, Vec::new())?;
+    image.add_segment(0x0120, vec![0; 0x10])?;
+    assert_eq!(image.segments().len(), 6);
+    assert_eq!(
+        cpu::Error::OverlappingSegments { address: 0x010f }.to_string(),
+        "segments overlap at 0x010f"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use std::io::{self, Write};
+
+use crate::{AccessKind, Instruction, MemoryAccess};
+
+/// The programmer visible registers at one point in a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegisterState {
+    pub a: u8,
+    /// The flags byte of PSW
+    pub flags: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl RegisterState {
+    /// PSW, as pushed by `PUSH PSW`
+    pub fn psw(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.flags])
+    }
+
+    fn to_bytes(self) -> [u8; 12] {
+        let [sp_low, sp_high] = self.sp.to_le_bytes();
+        let [pc_low, pc_high] = self.pc.to_le_bytes();
+        [
+            self.a, self.flags, self.b, self.c, self.d, self.e, self.h, self.l, sp_low, sp_high,
+            pc_low, pc_high,
+        ]
+    }
+}
+
+/// Everything one step did
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Where the instruction was fetched from, or the interrupted pc
+    pub pc: u16,
+    /// The bytes of the instruction. Empty for an instruction supplied by
+    /// an interrupt, which isn't read from memory
+    pub bytes: Vec<u8>,
+    pub instruction: Instruction,
+    pub before: RegisterState,
+    pub after: RegisterState,
+    pub memory_accesses: Vec<MemoryAccess>,
+    /// Clock cycles executed before this instruction
+    pub cycles: u64,
+    /// Clock cycles the instruction took
+    pub instruction_cycles: u8,
+}
+
+/// Somewhere to send trace records, see `Cpu::step_traced`
+///
+/// Any `FnMut(&TraceRecord)` closure is a sink.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord);
+}
+
+impl<F: FnMut(&TraceRecord)> TraceSink for F {
+    fn record(&mut self, record: &TraceRecord) {
+        self(record)
+    }
+}
+
+/// Writes one line per instruction in the format many 8080 emulators log,
+/// with the registers before the instruction executes and a tab, shown here
+/// as `\t`, before the instruction bytes:
+///
+/// ```text
+/// PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\t(31 00 24)
+/// ```
+///
+/// Write errors stop the output, and are returned by `into_inner`.
+#[derive(Debug)]
+pub struct TextTraceWriter<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TextTraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// The underlying writer, or the first error writing to it
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.writer),
+        }
+    }
+}
+
+impl<W: Write> TraceSink for TextTraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let before = &record.before;
+        let bytes = record
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let result = writeln!(
+            self.writer,
+            "PC: {:04X}, AF: {:04X}, BC: {:02X}{:02X}, DE: {:02X}{:02X}, HL: {:02X}{:02X}, SP: {:04X}, CYC: {}\t({})",
+            record.pc,
+            before.psw(),
+            before.b,
+            before.c,
+            before.d,
+            before.e,
+            before.h,
+            before.l,
+            before.sp,
+            record.cycles,
+            bytes
+        );
+        self.error = result.err();
+    }
+}
+
+/// Writes fixed layout little endian binary records after an 8 byte
+/// `8080TRC` header and version byte
+///
+/// Each record is the pc (2 bytes), the instruction length and up to 3
+/// instruction bytes padded with zeros, the registers before and after (12
+/// bytes each: A, flags, B, C, D, E, H, L, SP, PC), the cycle count before
+/// the instruction (8 bytes) and its length in cycles (1), then the number
+/// of memory accesses followed by each as kind (0 read, 1 write), address
+/// and value.
+///
+/// Write errors stop the output, and are returned by `into_inner`.
+#[derive(Debug)]
+pub struct BinaryTraceWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> BinaryTraceWriter<W> {
+    pub const HEADER: &'static [u8; 8] = b"8080TRC\x01";
+
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+            error: None,
+        }
+    }
+
+    /// The underlying writer, or the first error writing to it
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.writer),
+        }
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(Self::HEADER)?;
+            self.header_written = true;
+        }
+
+        let mut bytes = Vec::with_capacity(40 + record.memory_accesses.len() * 4);
+        bytes.extend(record.pc.to_le_bytes());
+        bytes.push(record.bytes.len() as u8);
+        let mut instruction = [0; 3];
+        for (byte, &value) in instruction.iter_mut().zip(&record.bytes) {
+            *byte = value;
+        }
+        bytes.extend(instruction);
+        bytes.extend(record.before.to_bytes());
+        bytes.extend(record.after.to_bytes());
+        bytes.extend(record.cycles.to_le_bytes());
+        bytes.push(record.instruction_cycles);
+        bytes.push(record.memory_accesses.len() as u8);
+        for access in &record.memory_accesses {
+            bytes.push(match access.kind {
+                AccessKind::Read => 0,
+                AccessKind::Write => 1,
+            });
+            bytes.extend(access.address.to_le_bytes());
+            bytes.push(access.value);
+        }
+
+        self.writer.write_all(&bytes)
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = self.write_record(record).err();
+        }
+    }
+}
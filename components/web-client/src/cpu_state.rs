@@ -1,25 +1,34 @@
-use eighty_eighty::Cpu;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use eighty_eighty::{Cpu, Io, Memory, MemoryBus};
 use yew::{function_component, html, use_state, Callback, Html, Properties};
 
-#[derive(Properties, PartialEq)]
-pub(crate) struct CpuStateProps<BusWriteCallback: FnMut(u8) + PartialEq> {
-    pub(crate) cpu: Cpu<BusWriteCallback>,
+#[derive(Properties)]
+pub(crate) struct CpuStateProps<IO: Io + 'static> {
+    pub(crate) cpu: Rc<RefCell<Cpu<IO>>>,
+    /// Bumped by the owner whenever the CPU changes
+    pub(crate) generation: u64,
+}
+
+// Comparing the CPUs themselves would compare their whole undo journals
+impl<IO: Io + 'static> PartialEq for CpuStateProps<IO> {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.cpu, &other.cpu) && self.generation == other.generation
+    }
 }
 
-fn make_row(memory: &[u8], row: u8, show_ascii: bool) -> Html {
-    let mut iter = memory.iter();
-    if row > 0 {
-        iter.nth((row * 16 - 1).into());
-    };
-    iter.take(16)
-        .map(|&i| html! { <div class="memory-cell memory-value">{if show_ascii { format!("{}", i as char) } else { format!("{:X}", i)} }</div> })
+fn make_row(memory: &Memory, row: u8, show_ascii: bool) -> Html {
+    let start = u16::from(row) * 16;
+    (start..start + 16)
+        .map(|address| memory.peek(address))
+        .map(|i| html! { <div class="memory-cell memory-value">{if show_ascii { format!("{}", i as char) } else { format!("{:X}", i)} }</div> })
         .collect::<Html>()
 }
 
 #[function_component(CpuState)]
-pub(crate) fn cpu_state<BusWriteCallback: FnMut(u8) + PartialEq>(
-    CpuStateProps { cpu }: &CpuStateProps<BusWriteCallback>,
-) -> Html {
+pub(crate) fn cpu_state<IO: Io + 'static>(CpuStateProps { cpu, .. }: &CpuStateProps<IO>) -> Html {
+    let cpu = cpu.borrow();
     let show_ascii = use_state(|| false);
 
     let headers = (0..=0xfu8)
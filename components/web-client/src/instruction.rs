@@ -1,16 +1,14 @@
-use eighty_eighty::Instruction;
+use eighty_eighty::DisassembledLine;
 use yew::{function_component, html, Html, Properties};
 
 #[derive(Properties, PartialEq)]
 pub(crate) struct InstructionPaneProps {
-    pub(crate) instructions: Vec<Instruction>,
+    pub(crate) lines: Vec<DisassembledLine>,
 }
 
 #[function_component(InstructionPane)]
-pub(crate) fn instruction_pane(
-    InstructionPaneProps { instructions }: &InstructionPaneProps,
-) -> Html {
+pub(crate) fn instruction_pane(InstructionPaneProps { lines }: &InstructionPaneProps) -> Html {
     html! { <div class="instruction-pane col">
-    {instructions.iter().enumerate().map(|(index, instr)| html! { <div class="row instruction-row"><div class="mr-md">{index + 1}</div> {instr}</div> }).collect::<Html>()}
+    {lines.iter().map(|line| html! { <div class="row instruction-row">{line.to_string()}</div> }).collect::<Html>()}
     </div> }
 }
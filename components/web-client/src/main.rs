@@ -4,10 +4,14 @@
 ///
 /// We want to render an arrow to the instruction next to be executed
 /// (cpu.pc === instruction.src_position)
-use eighty_eighty::Cpu;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use eighty_eighty::{Cpu, Debugger, Instruction, MemoryBus, StopReason};
+use wasm_bindgen::closure::Closure;
 use wasm_bindgen::JsCast;
-use web_sys::{DragEvent, File};
-use yew::{function_component, html, use_state, Callback, UseStateHandle};
+use web_sys::{DragEvent, Event, File, HtmlInputElement};
+use yew::{function_component, html, use_mut_ref, use_state, Callback};
 
 mod cpu_state;
 mod instruction;
@@ -18,26 +22,97 @@ fn get_first_file_from_drag_event(drag_event: DragEvent) -> Option<File> {
     drag_event.data_transfer()?.files()?.get(0)
 }
 
-fn handle_bus_val(i: u8) {
-    println!("Val on bus: {}", i);
+fn handle_bus_val(port: u8, value: u8) {
+    println!("Val on port {}: {}", port, value);
 }
 
-type CpuCallback = fn(u8) -> ();
+type CpuCallback = fn(u8, u8) -> ();
+
+/// How many steps can be undone with "Step Backward"
+const JOURNAL_LIMIT: usize = 200_000;
+
+/// How many clock cycles "Run" executes before letting the page update,
+/// about a tenth of a second on a 2 MHz 8080
+const RUN_BUDGET: u64 = 200_000;
+
+fn new_cpu() -> Cpu<CpuCallback> {
+    let mut cpu = Cpu::new(handle_bus_val as CpuCallback);
+    cpu.set_journal_limit(JOURNAL_LIMIT);
+    cpu
+}
+
+/// Run for `RUN_BUDGET` cycles, then re-render and schedule the next chunk
+/// with a timeout so the page stays responsive, until the debugger stops the
+/// CPU or `running` is cleared
+fn run_chunk<R: Fn() + Clone + 'static>(
+    cpu: Rc<RefCell<Cpu<CpuCallback>>>,
+    debugger: Rc<RefCell<Debugger>>,
+    running: Rc<RefCell<bool>>,
+    rerender: R,
+) {
+    if !*running.borrow() {
+        return;
+    }
+
+    let result = debugger
+        .borrow_mut()
+        .run_for(&mut cpu.borrow_mut(), RUN_BUDGET);
+    match result {
+        Ok(StopReason::BudgetExhausted) => {
+            rerender();
+            let next = Closure::once_into_js(move || run_chunk(cpu, debugger, running, rerender));
+            web_sys::window()
+                .expect("no window")
+                .set_timeout_with_callback_and_timeout_and_arguments_0(next.unchecked_ref(), 0)
+                .expect("failed to schedule the next chunk");
+        }
+        Ok(reason) => {
+            log::debug!("Stopped: {:?}", reason);
+            *running.borrow_mut() = false;
+            rerender();
+        }
+        Err(err) => {
+            log::error!("Failed to step cpu: {}", err);
+            *running.borrow_mut() = false;
+            rerender();
+        }
+    }
+}
 
 #[function_component(App)]
 fn app() -> Html {
-    let state_history: UseStateHandle<Vec<Cpu<CpuCallback>>> =
-        use_state(|| vec![Cpu::new(handle_bus_val as fn(u8) -> ())]);
+    // The CPU keeps its own undo journal, so it's mutated in place and
+    // `generation` is bumped to re-render after every change
+    let cpu: Rc<RefCell<Cpu<CpuCallback>>> = use_mut_ref(new_cpu);
+    let generation = use_state(|| 0u64);
+    let debugger: Rc<RefCell<Debugger>> = use_mut_ref(Debugger::new);
+    // Set while "Run" is going, and cleared to pause it
+    let running: Rc<RefCell<bool>> = use_mut_ref(|| false);
+
+    // A run keeps calling the closure from the render it started in, so the
+    // count lives outside of `generation`
+    let rerender = {
+        let generation = generation.clone();
+        let count = use_mut_ref(|| 0u64);
+        move || {
+            *count.borrow_mut() += 1;
+            generation.set(*count.borrow());
+        }
+    };
 
     let handle_file_drop = {
-        let state_history = state_history.clone();
+        let cpu = cpu.clone();
+        let running = running.clone();
+        let rerender = rerender.clone();
         Callback::from(move |drag_event: DragEvent| {
             let file = get_first_file_from_drag_event(drag_event);
             if file.is_none() {
                 return;
             };
 
-            let state_history = state_history.clone();
+            *running.borrow_mut() = false;
+            let cpu = cpu.clone();
+            let rerender = rerender.clone();
             wasm_bindgen_futures::spawn_local(async move {
                 let file_array_buffer =
                     wasm_bindgen_futures::JsFuture::from(file.unwrap().array_buffer())
@@ -53,7 +128,7 @@ fn app() -> Html {
                 let array_buffer = js_sys::Uint8Array::new(&array_buffer);
                 let vec = array_buffer.to_vec();
 
-                let mut new_cpu = Cpu::new(handle_bus_val as fn(u8) -> ());
+                let mut new_cpu = new_cpu();
 
                 let buffer_len = vec.len();
 
@@ -61,58 +136,104 @@ fn app() -> Html {
                     panic!("Error loading buf of len {} into CPU memory", buffer_len)
                 });
 
-                state_history.set(vec![new_cpu]);
+                *cpu.borrow_mut() = new_cpu;
+                rerender();
             });
         })
     };
 
-    let latest_cpu_state = (*state_history)[(*state_history).len() - 1];
-
-    let step_cpu = {
-        let state_history = state_history.clone();
-        move || {
-            let mut cpu = (*state_history)[(*state_history).len() - 1];
-            cpu.step().expect("Failed to step cpu");
-            let mut new_state_history = (*state_history).clone();
-            new_state_history.push(cpu);
-            state_history.set(new_state_history);
-        }
+    let handle_step_forward = {
+        let cpu = cpu.clone();
+        let running = running.clone();
+        let rerender = rerender.clone();
+        Callback::from(move |_| {
+            *running.borrow_mut() = false;
+            if let Err(err) = cpu.borrow_mut().step() {
+                log::error!("Failed to step cpu: {}", err);
+            }
+            rerender();
+        })
     };
 
-    let handle_step_forward = Callback::from(move |_| {
-        step_cpu();
-    });
-
     let handle_step_backward = {
-        let state_history = state_history.clone();
+        let cpu = cpu.clone();
+        let running = running.clone();
+        let rerender = rerender.clone();
         Callback::from(move |_| {
-            if (*state_history).len() > 1 {
-                let mut new_state_history = (*state_history).clone();
-                new_state_history.pop();
-                state_history.set(new_state_history);
+            *running.borrow_mut() = false;
+            if cpu.borrow_mut().step_back() {
+                rerender();
             }
         })
     };
 
     let handle_reset = {
-        let state_history = state_history.clone();
+        let cpu = cpu.clone();
+        let running = running.clone();
+        let rerender = rerender.clone();
         Callback::from(move |_| {
-            state_history.set(vec![Cpu::new(handle_bus_val as fn(u8) -> ())]);
+            *running.borrow_mut() = false;
+            *cpu.borrow_mut() = new_cpu();
+            rerender();
         })
     };
 
+    // An instruction typed in is assembled at the pc, to patch the program
+    // while stepping through it
+    let handle_patch = {
+        let cpu = cpu.clone();
+        let rerender = rerender.clone();
+        Callback::from(move |event: Event| {
+            let Some(input) = event
+                .target()
+                .and_then(|target| target.dyn_into::<HtmlInputElement>().ok())
+            else {
+                return;
+            };
+            let bytes = input
+                .value()
+                .parse::<Instruction>()
+                .and_then(|instruction| instruction.encode());
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    log::error!("Failed to assemble {:?}: {}", input.value(), err);
+                    return;
+                }
+            };
+
+            let mut cpu = cpu.borrow_mut();
+            let pc = cpu.pc();
+            for (offset, &byte) in bytes.iter().enumerate() {
+                cpu.memory_mut().poke(pc.wrapping_add(offset as u16), byte);
+            }
+            input.set_value("");
+            rerender();
+        })
+    };
+
+    // "Run" starts running in chunks, and clicking it again pauses
     let handle_run = {
+        let cpu = cpu.clone();
+        let running = running.clone();
         Callback::from(move |_| {
-            let mut new_state_history = (*state_history).clone();
-            let mut cpu = (*state_history)[(*state_history).len() - 1];
-            while !cpu.halted() && new_state_history.len() < 1000 {
-                cpu.step().expect("Failed to step cpu");
-                new_state_history.push(cpu);
+            let was_running = running.replace(false);
+            if was_running {
+                rerender();
+            } else {
+                *running.borrow_mut() = true;
+                run_chunk(
+                    cpu.clone(),
+                    debugger.clone(),
+                    running.clone(),
+                    rerender.clone(),
+                );
             }
-            state_history.set(new_state_history);
         })
     };
 
+    let run_label = if *running.borrow() { "Pause" } else { "Run" };
+
     log::debug!("App function called!");
 
     html! {
@@ -120,12 +241,15 @@ fn app() -> Html {
             <b>{"Binary File:"}</b>
             <input ondrop={handle_file_drop} type={"file"}/>
             <div class="mt-md row">
-                <button class="mr-lg" onclick={handle_run}>{"Run"}</button>
+                <button class="mr-lg" onclick={handle_run}>{run_label}</button>
                 <button onclick={handle_step_backward}>{"< Step Backward"}</button>
                 <button onclick={handle_reset}>{"Reset"}</button>
                 <button onclick={handle_step_forward}>{"Step Forward >"}</button>
             </div>
-            <CpuState<CpuCallback> cpu={latest_cpu_state}/>
+            <div class="mt-md row">
+                <input onchange={handle_patch} type={"text"} placeholder={"Patch at pc, e.g. MVI A,42H"}/>
+            </div>
+            <CpuState<CpuCallback> cpu={cpu} generation={*generation}/>
         </div>
     }
 }